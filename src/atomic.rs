@@ -0,0 +1,189 @@
+//! Replacing a file atomically: the new content goes to a temporary file in the same directory,
+//! which is synced to disk and then renamed over the target. A crash halfway through leaves the
+//! old file, or none, but never a truncated one. A symbolic link is followed, the file it
+//! points to is replaced and the link is kept.
+//!
+//! The directory is synced after the rename as well, so that the rename survives a crash. A
+//! failure there is ignored, the file is in place by then and an error would tell the caller
+//! that the write failed.
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::error::Result;
+
+/// Write `path` through `write`, refusing to replace an existing file if `no_clobber`.
+pub(crate) fn write_file(path: &Path, no_clobber: bool, write: impl FnOnce(&mut File) -> Result<()>)
+    -> Result<()> {
+    let path = &resolve_links(path)?;
+    if no_clobber && path.exists() {
+        return Err(already_exists(path).into());
+    }
+    let (tmp, mut file) = create_temp(path)?;
+    let result = write(&mut file)
+        .and_then(|_| Ok(file.sync_all()?))
+        .and_then(|_| {
+            drop(file);
+            Ok(persist(&tmp, path, no_clobber)?)
+        });
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+/// The file a chain of symbolic links ends in, which need not exist, `path` itself if it is no
+/// link.
+fn resolve_links(path: &Path) -> io::Result<PathBuf> {
+    let mut path = path.to_owned();
+    // the limit of links followed on Linux
+    for _ in 0 .. 40 {
+        match fs::symlink_metadata(&path) {
+            Ok(meta) if meta.file_type().is_symlink() => {
+                let target = fs::read_link(&path)?;
+                // a relative target is relative to the directory of the link
+                path = path.parent().map_or_else(|| target.clone(), |dir| dir.join(&target));
+            },
+            _ => return Ok(path),
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidInput,
+                       format!("too many levels of symbolic links in {}", path.display())))
+}
+
+/// Create a new file next to `path`, with the permissions of `path` if it exists.
+fn create_temp(path: &Path) -> io::Result<(PathBuf, File)> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let name = path.file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+    loop {
+        let mut tmp_name = std::ffi::OsString::from(".");
+        tmp_name.push(name);
+        tmp_name.push(format!(".{}.{}.tmp", process::id(), COUNTER.fetch_add(1, Ordering::Relaxed)));
+        let tmp = path.with_file_name(tmp_name);
+        match OpenOptions::new().write(true).create_new(true).open(&tmp) {
+            Ok(file) => {
+                if let Ok(meta) = fs::metadata(path) {
+                    file.set_permissions(meta.permissions())?;
+                }
+                return Ok((tmp, file));
+            },
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Move `tmp` to `path`. Without clobbering, a hard link is made instead, which fails if `path`
+/// exists by now, and `tmp` is removed.
+fn persist(tmp: &Path, path: &Path, no_clobber: bool) -> io::Result<()> {
+    if no_clobber {
+        match fs::hard_link(tmp, path) {
+            Ok(()) => fs::remove_file(tmp)?,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return Err(already_exists(path)),
+            // file systems without hard links, only the check on entry protects `path`
+            Err(_) => fs::rename(tmp, path)?,
+        }
+    } else {
+        fs::rename(tmp, path)?;
+    }
+    // some file systems cannot sync a directory, the rename is only less durable there
+    let _ = sync_dir(path);
+    Ok(())
+}
+
+/// Sync the directory of `path`, so that the rename itself survives a crash.
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+fn already_exists(path: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    use crate::error::ChgError;
+
+    fn leftovers(dir: &Path) -> Vec<PathBuf> {
+        fs::read_dir(dir).unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.extension().is_some_and(|e| e == "tmp"))
+            .collect()
+    }
+
+    #[test]
+    fn test_write_file() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("vaspchg_atomic_{}", process::id()));
+        fs::create_dir_all(&dir)?;
+        let path = dir.join("CHGCAR");
+
+        write_file(&path, false, |f| Ok(f.write_all(b"first")?))?;
+        write_file(&path, false, |f| Ok(f.write_all(b"second")?))?;
+        assert_eq!(fs::read(&path)?, b"second");
+
+        // a failed write leaves the old file alone
+        let failed = write_file(&path, false, |f| {
+            f.write_all(b"trunc")?;
+            Err(io::Error::other("disk full").into())
+        });
+        assert!(failed.is_err());
+        assert_eq!(fs::read(&path)?, b"second");
+
+        match write_file(&path, true, |f| Ok(f.write_all(b"third")?)) {
+            Err(ChgError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::AlreadyExists),
+            _ => panic!("existing file overwritten"),
+        }
+        assert_eq!(fs::read(&path)?, b"second");
+        write_file(&dir.join("CHG"), true, |f| Ok(f.write_all(b"new")?))?;
+        assert_eq!(fs::read(dir.join("CHG"))?, b"new");
+
+        assert_eq!(leftovers(&dir), Vec::<PathBuf>::new());
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_symlink() -> Result<()> {
+        use std::os::unix::fs::symlink;
+
+        let dir = std::env::temp_dir().join(format!("vaspchg_atomic_link_{}", process::id()));
+        fs::create_dir_all(dir.join("run"))?;
+        let link = dir.join("CHGCAR");
+        fs::write(dir.join("run/CHGCAR"), b"old")?;
+        symlink("run/CHGCAR", &link)?;
+
+        write_file(&link, false, |f| Ok(f.write_all(b"new")?))?;
+        assert!(fs::symlink_metadata(&link)?.file_type().is_symlink());
+        assert_eq!(fs::read(dir.join("run/CHGCAR"))?, b"new");
+        assert!(write_file(&link, true, |f| Ok(f.write_all(b"newer")?)).is_err());
+
+        // a dangling link gets its target created
+        symlink(dir.join("run/CHG"), dir.join("CHG"))?;
+        write_file(&dir.join("CHG"), true, |f| Ok(f.write_all(b"chg")?))?;
+        assert!(fs::symlink_metadata(dir.join("CHG"))?.file_type().is_symlink());
+        assert_eq!(fs::read(dir.join("run/CHG"))?, b"chg");
+
+        assert_eq!(leftovers(&dir), Vec::<PathBuf>::new());
+        assert_eq!(leftovers(&dir.join("run")), Vec::<PathBuf>::new());
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
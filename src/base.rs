@@ -1,13 +1,40 @@
-use std::io::{self, Write, BufRead, Seek, SeekFrom, BufReader};
+use std::borrow::Cow;
+use std::fmt::Write as _;
+use std::io::{self, Write, Read, BufRead, BufReader, BufWriter, Seek, SeekFrom};
+use std::ops::Range;
 use std::path::Path;
 use std::fs::File;
 
-use vasp_poscar::{
-    Poscar,
-    failure::Error as PoscarError,
-};
-use ndarray::{Array3};
-use regex::Regex;
+use vasp_poscar::{Coords, Poscar, ScaleLine};
+use ndarray::{Array3, Zip};
+#[cfg(feature = "json")]
+use sha2::{Digest, Sha256};
+
+use crate::atomic;
+use crate::compress::{self, Codec, Compression, Encoder};
+use crate::error::{ChgError, Location, Result, Section};
+use crate::float::ChgFloat;
+use crate::fortran::{self, Token};
+use crate::lossless::{self, Source};
+use crate::meta::{ChgMeta, Component, ComponentOffsets, SectionStart};
+use crate::options::{NumberFormat, ReadOptions, OverflowPolicy, WriteOptions};
+#[cfg(feature = "json")]
+use crate::provenance::{HashReader, SourceFile};
+use crate::provenance::{self, Provenance};
+use crate::reader::{Buffered, LineReader, LineSource};
+use crate::species;
+
+/// Lines collected before a batch is parsed in parallel, bounds the memory used for the text.
+#[cfg(feature = "rayon")]
+const PAR_BATCH_LINES: usize = 1 << 16;
+/// Lines parsed by one parallel task.
+#[cfg(feature = "rayon")]
+const PAR_CHUNK_LINES: usize = 1 << 10;
+/// Grid rows formatted into one buffer before it is written.
+const WRITE_CHUNK_ROWS: usize = 1 << 12;
+/// Buffers of `WRITE_CHUNK_ROWS` rows formatted in parallel, bounds the memory used for the text.
+#[cfg(feature = "rayon")]
+const PAR_WRITE_BATCH: usize = 64;
 
 /// Main struct of volumetric data
 ///
@@ -77,204 +104,474 @@ use regex::Regex;
 /// Also, CHG stores the total charge density of all the electrons below fermi level in all kpoint,
 /// all bands.
 ///
-pub struct ChgBase {
+pub struct ChgBase<T = f64> {
     pos:        Poscar,
-    chg:        Array3<f64>,
+    chg:        Array3<T>,
     aug:        Option<String>,
     ngrid:      [usize; 3],
+    axis_scales: Option<[f64; 3]>,
+    chgtype:    ChgType,
+    source:     Option<Source>,
+    provenance: Provenance,
 
     // Optional part
-    chgdiff:    Vec<Array3<f64>>,
+    chgdiff:    Vec<Array3<T>>,
     augdiff:    Vec<String>,
+    magmom:     Vec<String>,
 }
 
 /// Supported formats in saving
+///
+/// `Chgcar` and `Parchg` write 5 values per row as `0.44062142953E+00`, `Chg` writes VASP's
+/// more compact CHG layout, 10 values per row as `0.44062E+00`. The number formatting can be
+/// changed with [`WriteOptions::format`](struct.WriteOptions.html#structfield.format).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ChgType {
     Chg,
     Chgcar,
     Parchg,
 }
 
+impl ChgType {
+    /// Layout of a file whose first grid row has `row` values: a file with an `augmentation
+    /// occupancies` line is a CHGCAR, one with more than 5 values per row is a CHG.
+    pub(crate) fn _detect(row: usize, has_aug: bool) -> Self {
+        match (has_aug, row > 5) {
+            (true, _) => ChgType::Chgcar,
+            (false, true) => ChgType::Chg,
+            (false, false) => ChgType::Parchg,
+        }
+    }
+}
+
 
-impl ChgBase {
+impl<T: ChgFloat> ChgBase<T> {
     /// Construct a ChgBase with charge grids and poscar object.
-    pub fn from_builder(chg: Array3<f64>, chgdiff: Vec<Array3<f64>>, pos: Poscar) -> Self {
+    pub(crate) fn _from_parts(meta: &ChgMeta, chg: Array3<T>, aug: Option<String>,
+                              chgdiff: Vec<Array3<T>>, augdiff: Vec<String>, magmom: Vec<String>) -> Self {
+        let pos = meta.get_poscar().clone();
+        let axis_scales = meta.get_axis_scales();
+        let chgtype = meta.get_chgtype();
+        let ngrid = chg.shape().to_owned();
+        let ngrid = [ngrid[0], ngrid[1], ngrid[2]];
+        let provenance = Provenance::default();
+        Self { pos, chg, aug, ngrid, axis_scales, chgtype, source: None, provenance, chgdiff, augdiff,
+               magmom }
+    }
+
+    pub fn from_builder(chg: Array3<T>, chgdiff: Vec<Array3<T>>, pos: Poscar) -> Self {
         let aug = None;
         let ngrid = chg.shape().to_owned();
         let ngrid = [ngrid[0], ngrid[1], ngrid[2]];
         let augdiff = vec![];
+        let magmom = vec![];
+        let axis_scales = None;
+        let chgtype = ChgType::Parchg;
+        let source = None;
+        let provenance = Provenance::default();
 
-        Self { pos, chg, aug, ngrid, chgdiff, augdiff }
+        Self { pos, chg, aug, ngrid, axis_scales, chgtype, source, provenance, chgdiff, augdiff, magmom }
     }
 
-    /// Read volumetric data from existing file.
+    /// Read volumetric data from existing file into grids of `T`, e.g. `f32` to halve the memory.
     ///
-    /// Usually you can use &str as path(, or &std::path::Path, which is my preference).
-    pub fn from_file(path: &(impl AsRef<Path> + ?Sized)) -> io::Result<Self> {
+    /// ```no_run
+    /// use vaspchg_rs::{ChgBase, ReadOptions};
+    ///
+    /// let chg = ChgBase::<f32>::from_file_as("CHGCAR", &ReadOptions::default()).unwrap();
+    /// let chg64 = chg.convert::<f64>();
+    /// ```
+    pub fn from_file_as(path: &(impl AsRef<Path> + ?Sized), opts: &ReadOptions) -> Result<Self> {
+        #[cfg(not(feature = "json"))]
+        provenance::check_read_options(opts)?;
         let file = File::open(path)?;
-        let mut file = BufReader::new(file);
-        Self::from_reader(&mut file)
+        #[cfg(feature = "json")]
+        if provenance::hash_on_read(path.as_ref(), opts) {
+            return Self::_from_file_hashed(file, path.as_ref(), opts);
+        }
+        let reader = compress::decompress(BufReader::new(file))?;
+        ChgBase::_read_all(&mut LineReader::new(reader), opts, Some(path.as_ref()))
     }
 
-    /// Read volumetric data from reading buffer, and that buffer should implemented `Seek` trait.
-    ///
-    /// See the unit tests in this source file for detailed usage.
-    pub fn from_reader(file: &mut (impl BufRead+Seek)) -> io::Result<Self> {
-        file.seek(SeekFrom::Start(0))?;
-        let pos = Self::_read_poscar(file).unwrap();
-        let chg = Self::_read_chg(file)? / pos.scaled_volume();
-        let aug = Self::_read_raw_aug(file).ok();
-        let (chgdiff, augdiff) = Self::_read_optional_parts(file).unwrap();
-        let ngrid = chg.shape().to_owned();
-        let ngrid = [ngrid[0], ngrid[1], ngrid[2]];
-        Ok(
-            ChgBase { pos, chg, aug, chgdiff, augdiff, ngrid }
-        )
+    /// Read a file and pick up its provenance log, see `_add_read_source`.
+    #[cfg(feature = "json")]
+    fn _from_file_hashed(mut file: File, path: &Path, opts: &ReadOptions) -> Result<Self> {
+        let mut hasher = Sha256::new();
+        let reader = compress::decompress(BufReader::new(HashReader { inner: &mut file, hasher: &mut hasher }))?;
+        let mut chg = ChgBase::_read_all(&mut LineReader::new(reader), opts, Some(path))?;
+        // the hash is of the whole file, also what the parser did not need to read
+        io::copy(&mut HashReader { inner: &mut file, hasher: &mut hasher }, &mut io::sink())?;
+        chg._add_read_source(path, provenance::hex(hasher), opts);
+        Ok(chg)
     }
 
-    fn _read_optional_parts(file: &mut (impl BufRead+Seek))
-        -> io::Result<(Vec<Array3<f64>>, Vec<String>)> {
-        let mut chgdiff = vec![];
-        let mut augdiff = vec![];
+    /// Read volumetric data from reading buffer into grids of `T`.
+    pub fn from_reader_as(file: &mut impl BufRead, opts: &ReadOptions) -> Result<Self> {
+        ChgBase::_read_all(&mut LineReader::new(file), opts, None)
+    }
 
-        while let Ok(chg) = Self::_read_chg(file) {
-            chgdiff.push(chg);
-            if let Ok(aug) = Self::_read_raw_aug(file) {
-                augdiff.push(aug);
-            }
+    /// Read volumetric data from bytes in memory into grids of `T`.
+    pub fn from_bytes_as(bytes: &[u8], opts: &ReadOptions) -> Result<Self> {
+        match Codec::sniff(bytes) {
+            Codec::Plain => ChgBase::_read_all(&mut LineReader::from_slice(bytes), opts, None),
+            _ => Self::from_reader_as(&mut compress::decompress(bytes)?, opts),
         }
-        Ok((chgdiff, augdiff))
     }
 
-    fn _read_poscar(file: &mut (impl BufRead+Seek)) -> Result<Poscar, PoscarError> {
-        let mut buf = String::new();
-        while let Ok(n) = file.read_line(&mut buf) {
-            if n + 1 == buf.len() - buf.trim_end().len() {
-                break;
-            }
+    /// Read volumetric data from a memory-mapped file into grids of `T`, see `from_file_mmap`.
+    #[cfg(feature = "mmap")]
+    pub fn from_file_mmap_as(path: &(impl AsRef<Path> + ?Sized), opts: &ReadOptions) -> Result<Self> {
+        #[cfg(not(feature = "json"))]
+        provenance::check_read_options(opts)?;
+        let file = File::open(path)?;
+        if file.metadata()?.len() == 0 {
+            return ChgBase::_read_all(&mut LineReader::from_slice(&[]), opts, Some(path.as_ref()));
+        }
+        // SAFETY: the map is only read, and the caller is told not to modify the file meanwhile
+        let map = unsafe { memmap2::Mmap::map(&file)? };
+        match Codec::sniff(&map) {
+            Codec::Plain => {
+                #[allow(unused_mut)]
+                let mut chg = ChgBase::_read_all(&mut LineReader::from_slice(&map), opts, Some(path.as_ref()))?;
+                #[cfg(feature = "json")]
+                if provenance::hash_on_read(path.as_ref(), opts) {
+                    chg._add_read_source(path.as_ref(), provenance::hex(Sha256::new_with_prefix(&map[..])), opts);
+                }
+                Ok(chg)
+            },
+            _ => Self::from_file_as(path, opts),
         }
-        Poscar::from_reader(
-            io::Cursor::new(buf.into_bytes())
-        )
     }
 
-    fn _read_chg(file: &mut (impl BufRead+Seek)) -> io::Result<Array3<f64>> {
-        let mut lines = file.lines().map(|l| l.unwrap());
-        let nl = lines.next();
-        let ngrid_line = if nl.is_none() {
-            return Err(
-                io::Error::new(io::ErrorKind::Other, "End of file reached.")
-            );
-        } else { nl.unwrap() };
-        let ngrid = ngrid_line.split_ascii_whitespace()
-            .take(3)
-            .map(|t| t.parse::<usize>().unwrap())
-            .collect::<Vec<_>>();
-
-        let mut len = 0;
-        let buf = lines
-            .take_while(|l| {
-                if !l.starts_with("aug") {
-                    true
-                } else {
-                    len = l.len() as i64 + 1;   // "+1" means taking account of the '\n'.
-                    false
-                }
-            })
-            .fold(Vec::new(), |mut acc, l| {
-                acc.extend(l.split_ascii_whitespace()
-                    .map(|t| t.parse::<f64>().unwrap()));
-                acc
-            })
-            .into_iter()
-            .take(ngrid.iter().product())
-            .collect::<Vec<_>>();
-
-        file.seek(SeekFrom::Current(0 - len))?; // move cursor back in front of 'augmentation'
-        let chg = Array3::<f64>::from_shape_vec((ngrid[2], ngrid[1], ngrid[0]), buf).unwrap();
-        Ok(
-            chg.reversed_axes().as_standard_layout().into_owned()
-        )
+    /// Take the history from the sidecar of `path`, whose SHA-256 is `sha256`, and record the
+    /// file after it, or without a sidecar only if `opts.provenance` is set.
+    #[cfg(feature = "json")]
+    fn _add_read_source(&mut self, path: &Path, sha256: String, opts: &ReadOptions) {
+        match provenance::read_sidecar(path, &sha256) {
+            Some(log) => self.provenance = log,
+            None if !opts.provenance => return,
+            None => (),
+        }
+        self.provenance.add_source(SourceFile { path: path.display().to_string(), sha256 });
     }
 
-    fn _read_raw_aug(file: &mut (impl BufRead+Seek)) -> io::Result<String> {
-        let re = Regex::new(r"^(\s*\d+){3}").unwrap();
-        let lines = file.lines().map(|l| l.unwrap());
-        let mut len = 0;
-        let raw_aug = lines
-            .take_while(|l| {
-                if !re.is_match(l) {
-                    true
-                } else {
-                    len = l.len() as i64 + 1;
-                    false
-                } })                // take until " NGXF NGYF NGZF"
-            .fold(String::new(), |acc, x| acc + &x + "\n");  // Join all the lines with \n
-        file.seek(SeekFrom::Current(0 - len))?;
-        Ok(raw_aug)
-    }
-
-    fn _write_chg(file: &mut impl Write, chg: &Array3<f64>, num_per_row: usize) -> io::Result<()> {
-        let chg = chg.clone().reversed_axes();
-        chg.shape().iter().rev()
-            .try_for_each(|n| write!(file, " {:>4}", n))?;
-        write!(file, "\n")?;
-        chg.as_standard_layout().into_owned().as_slice().unwrap()
-            .chunks(num_per_row)
-            .try_for_each(|l| {
-                l.iter().try_for_each(|n| write!(file, " {:>17.10E}", n)).unwrap();
-                write!(file, "\n")
-            })?;
+    /// Convert the grids to another float type, e.g. `chg.convert::<f32>()`.
+    pub fn convert<U: ChgFloat>(&self) -> ChgBase<U> {
+        let convert = |chg: &Array3<T>| chg.mapv(|v| U::from_f64(v.to_f64()));
+        ChgBase {
+            pos:        self.pos.clone(),
+            chg:        convert(&self.chg),
+            aug:        self.aug.clone(),
+            ngrid:      self.ngrid,
+            axis_scales: self.axis_scales,
+            chgtype:    self.chgtype,
+            source:     self.source.clone(),
+            provenance: self.provenance.clone(),
+            chgdiff:    self.chgdiff.iter().map(convert).collect(),
+            augdiff:    self.augdiff.clone(),
+            magmom:     self.magmom.clone(),
+        }
+    }
+
+    /// Write a grid in file order, x fastest, with the values multiplied by `volume`.
+    fn _write_chg(file: &mut impl Write, chg: &Array3<T>, volume: f64, format: &NumberFormat)
+        -> io::Result<()> {
+        ChgBase::_write_ngrid(file, chg.shape())?;
+        let nrows = chg.len().div_ceil(format.per_row);
+        #[cfg(not(feature = "rayon"))]
+        return Self::_write_rows(file, chg, volume, format, nrows, WRITE_CHUNK_ROWS);
+        #[cfg(feature = "rayon")]
+        return Self::_write_rows_par(file, chg, volume, format, nrows, PAR_WRITE_BATCH, WRITE_CHUNK_ROWS);
+    }
+
+    /// Write the first `nrows` rows of a grid, `chunk` rows are formatted into a buffer at a time.
+    #[cfg(not(feature = "rayon"))]
+    fn _write_rows(file: &mut impl Write, chg: &Array3<T>, volume: f64, format: &NumberFormat,
+                   nrows: usize, chunk: usize) -> io::Result<()> {
+        let mut buf = String::new();
+        for start in (0 .. nrows).step_by(chunk) {
+            buf.clear();
+            Self::_format_rows(chg, volume, format, start .. nrows.min(start + chunk), &mut buf);
+            file.write_all(buf.as_bytes())?;
+        }
         Ok(())
     }
 
-    /// Write ChgBase object to a write-buffer.
-    ///
-    /// Note: augmentation data is required if `chgtype == ChgType::Chgcar`
-    pub fn write_writer(&self, file: &mut impl Write, chgtype: ChgType) -> io::Result<()> {
-        write!(file, "{:>9.6}", self.get_poscar())?;
-        write!(file, "\n")?;
-        let chg = self.get_total_chg() * self.get_poscar().scaled_volume();
-        Self::_write_chg(file, &chg, 5)?;
-        match chgtype {
-            ChgType::Chgcar => {
-                assert!(self.get_total_aug().is_some(),
-                        "No augmentation data found, cannot save as CHGCAR");
-                write!(file, "{}", self.get_total_aug().unwrap())?;
-            },
-            _ => {}
+    /// Write the first `nrows` rows of a grid, `batch` buffers of `chunk` rows each are
+    /// formatted in parallel, then written in order.
+    #[cfg(feature = "rayon")]
+    fn _write_rows_par(file: &mut impl Write, chg: &Array3<T>, volume: f64, format: &NumberFormat,
+                       nrows: usize, batch: usize, chunk: usize) -> io::Result<()> {
+        use rayon::prelude::*;
+
+        let mut bufs = vec![String::new(); batch];
+        for first in (0 .. nrows).step_by(batch * chunk) {
+            bufs.par_iter_mut().enumerate().for_each(|(k, buf)| {
+                buf.clear();
+                let start = nrows.min(first + k * chunk);
+                Self::_format_rows(chg, volume, format, start .. nrows.min(start + chunk), buf);
+            });
+            bufs.iter().try_for_each(|buf| file.write_all(buf.as_bytes()))?;
+        }
+        Ok(())
+    }
+
+    /// Format `rows` of a grid in file order into `out`, each row ends with a newline.
+    fn _format_rows(chg: &Array3<T>, volume: f64, format: &NumberFormat, rows: Range<usize>,
+                    out: &mut String) {
+        let (nx, ny, len) = (chg.shape()[0], chg.shape()[1], chg.len());
+        for row in rows {
+            for i in row * format.per_row .. len.min((row + 1) * format.per_row) {
+                format.write_value(out, chg[[i % nx, i / nx % ny, i / (nx * ny)]].to_f64() * volume);
+            }
+            out.push('\n');
+        }
+    }
+
+    /// Write the whole text, the parts of `source` that are not modified are copied verbatim.
+    /// `augs` are the augmentation occupancies of each component, empty unless writing a CHGCAR,
+    /// the magnetic moments follow them in every layout.
+    fn _write_plain(&self, file: &mut impl Write, format: &NumberFormat, augs: &[Cow<'_, str>],
+                    title: Option<&str>, source: Option<&Source>) -> Result<()> {
+        match source.filter(|_| title.is_none())
+            .and_then(|s| s.header(lossless::header_key(&self.pos, self.axis_scales))) {
+            Some(text) => file.write_all(text.as_bytes())?,
+            None => ChgBase::_write_header(file, &self.pos, self.axis_scales, title)?,
+        }
+        let volume = self.get_poscar().scaled_volume();
+        Self::_write_grid(file, self.get_total_chg(), volume, format, source.map(|s| (s, 0)))?;
+        if let Some(source) = source {
+            file.write_all(source.rest(0).as_bytes())?;
+        } else {
+            if let Some(aug) = augs.first() {
+                write!(file, "{}", aug)?;
+            }
+            self._write_magmom(file, 0)?;
         }
 
         for i in 0 .. self.get_diff_chg().len() {
-            Self::_write_chg(file, &self.get_diff_chg()[i], 5)?;
-            match chgtype {
-                ChgType::Chgcar => {
-                    write!(file, "{}\n", &self.get_diff_aug()[i])?;
-                },
-                _ => {}
+            Self::_write_grid(file, &self.get_diff_chg()[i], 1.0, format, source.map(|s| (s, i + 1)))?;
+            if let Some(source) = source {
+                file.write_all(source.rest(i + 1).as_bytes())?;
+            } else {
+                if let Some(aug) = augs.get(i + 1) {
+                    writeln!(file, "{}", aug)?;
+                }
+                self._write_magmom(file, i + 1)?;
             }
         }
 
         Ok(())
     }
 
+    /// Write the magnetic moments after the `k`-th component, if there are any.
+    fn _write_magmom(&self, file: &mut impl Write, k: usize) -> io::Result<()> {
+        match self.magmom.get(k) {
+            Some(magmom) => file.write_all(magmom.as_bytes()),
+            None => Ok(()),
+        }
+    }
+
+    /// Write a grid, copied from the `k`-th grid of the source if given and not modified.
+    fn _write_grid(file: &mut impl Write, chg: &Array3<T>, volume: f64, format: &NumberFormat,
+                   source: Option<(&Source, usize)>) -> io::Result<()> {
+        match source.and_then(|(s, k)| s.grid(k, lossless::grid_key(chg, volume))) {
+            Some(text) => file.write_all(text.as_bytes()),
+            None => Self::_write_chg(file, chg, volume, format),
+        }
+    }
+
+    /// Augmentation occupancies of the `k`-th component, 0 for the total density. Zero
+    /// occupancies are made up from `lmmax` if there are none or their text is blank, e.g.
+    /// for a file read without them.
+    fn _aug_text(&self, k: usize, lmmax: Option<&[usize]>) -> Result<Cow<'_, str>> {
+        let aug = match k {
+            0 => self.aug.as_deref(),
+            k => self.augdiff.get(k - 1).map(String::as_str),
+        };
+        match (aug, lmmax) {
+            (Some(aug), _) if !aug.trim().is_empty() => Ok(Cow::Borrowed(aug)),
+            (_, Some(lmmax)) => Ok(Cow::Owned(ChgBase::_zero_aug(lmmax, self.pos.num_sites())?)),
+            (_, None) => Err(ChgError::MissingAugmentation(Component::at(k))),
+        }
+    }
+
+    /// Write ChgBase object to a write-buffer.
+    ///
+    /// Note: augmentation occupancies are required if `chgtype == ChgType::Chgcar`, unless
+    /// [`WriteOptions::zero_aug_lmmax`](struct.WriteOptions.html#structfield.zero_aug_lmmax)
+    /// is given, otherwise `ChgError::MissingAugmentation` is returned.
+    pub fn write_writer(&self, file: &mut impl Write, chgtype: ChgType) -> Result<()> {
+        self.write_writer_with(file, chgtype, &WriteOptions::default())
+    }
+
+    /// Write ChgBase object to a write-buffer with options, e.g. compression or number formatting.
+    ///
+    /// The output is compressed as it is written, the whole text is never held in memory.
+    ///
+    /// If this was read with [`ReadOptions::lossless`](struct.ReadOptions.html#structfield.lossless)
+    /// and is written as `get_chgtype()` without `opts.format`, the header and the grids that are
+    /// not modified are written exactly as they were read, and so are the augmentation
+    /// occupancies.
+    pub fn write_writer_with(&self, file: &mut impl Write, chgtype: ChgType, opts: &WriteOptions)
+        -> Result<()> {
+        let format = opts.format.unwrap_or_else(|| NumberFormat::default_for(chgtype));
+        format.check()?;
+        let augs = match chgtype {
+            ChgType::Chgcar => (0 ..= self.chgdiff.len())
+                .map(|k| self._aug_text(k, opts.zero_aug_lmmax.as_deref()))
+                .collect::<Result<Vec<_>>>()?,
+            ChgType::Chg | ChgType::Parchg => vec![],
+        };
+        let title = Some(self.provenance.title())
+            .filter(|_| opts.provenance_title && !self.provenance.is_empty());
+        let source = self.source.as_ref()
+            .filter(|s| opts.format.is_none() && chgtype == self.chgtype && s.ngrids() == 1 + self.chgdiff.len());
+        let compression = opts.compression.unwrap_or(Compression::Plain);
+        let mut file = BufWriter::new(Encoder::new(file, compression)?);
+        self._write_plain(&mut file, &format, &augs, title.as_deref(), source)?;
+        file.into_inner().map_err(|e| e.into_error())?.finish()?;
+        Ok(())
+    }
+
     /// Write ChgBase object to a new file or overwrite the old file.
     ///
-    /// Note: augmentation data is required if `chgtype == ChgType::Chgcar`
-    pub fn write_file(&self, path: &(impl AsRef<Path> + ?Sized), chgtype: ChgType) -> io::Result<()> {
-        let mut file = File::create(path)?;
-        self.write_writer(&mut file, chgtype)?;
+    /// The output is compressed if the extension of `path` is one of `.gz`, `.xz`, `.zst` or
+    /// `.bz2`, which requires the corresponding cargo feature.
+    ///
+    /// Note: augmentation occupancies are required if `chgtype == ChgType::Chgcar`, see
+    /// `write_writer`.
+    pub fn write_file(&self, path: &(impl AsRef<Path> + ?Sized), chgtype: ChgType) -> Result<()> {
+        self.write_file_with(path, chgtype, &WriteOptions::default())
+    }
+
+    /// Write ChgBase object to a new file or overwrite the old file with options.
+    ///
+    /// The data is written to a temporary file in the same directory, synced to disk and then
+    /// renamed to `path`, so a failed write never leaves a truncated file behind. The directory
+    /// is synced after the rename too, a failure there is not reported. If `path` is a symbolic
+    /// link, the file it points to is replaced and the link is kept. With `opts.no_clobber`, an
+    /// existing `path` is an error of kind `AlreadyExists`.
+    ///
+    /// With `opts.provenance_sidecar`, the provenance log is written in full to
+    /// `<path>.provenance.json` afterwards, where `from_file` finds it.
+    pub fn write_file_with(&self, path: &(impl AsRef<Path> + ?Sized), chgtype: ChgType,
+                           opts: &WriteOptions) -> Result<()> {
+        let mut opts = opts.clone();
+        opts.compression = opts.compression.or_else(|| Some(Compression::from_path(path)));
+        if opts.provenance_sidecar {
+            return self._write_file_with_sidecar(path.as_ref(), chgtype, &opts);
+        }
+        atomic::write_file(path.as_ref(), opts.no_clobber,
+                           |file| self.write_writer_with(file, chgtype, &opts))
+    }
+
+    /// Write `path`, then its provenance sidecar with the hash of what was written.
+    #[cfg(feature = "json")]
+    fn _write_file_with_sidecar(&self, path: &Path, chgtype: ChgType, opts: &WriteOptions) -> Result<()> {
+        let mut hasher = Sha256::new();
+        atomic::write_file(path, opts.no_clobber, |file| {
+            let mut file = provenance::HashWriter { inner: file, hasher: &mut hasher };
+            self.write_writer_with(&mut file, chgtype, opts)
+        })?;
+        provenance::write_sidecar(path, &self.provenance, &provenance::hex(hasher))
+    }
+
+    #[cfg(not(feature = "json"))]
+    fn _write_file_with_sidecar(&self, _path: &Path, _chgtype: ChgType, _opts: &WriteOptions) -> Result<()> {
+        Err(provenance::requires_json("WriteOptions::provenance_sidecar").into())
+    }
+
+    /// Density of the spin-up electrons of an ISPIN = 2 file, `(rho + m) / 2` from the total
+    /// density and the magnetization in the first diff component.
+    ///
+    /// The result has no diff components and no augmentation occupancies, like a PARCHG. It is
+    /// an error if there is no diff component, or three of a non-collinear file.
+    pub fn spin_up(&self) -> Result<Self> {
+        self._spin_channel(1.0, "spin up")
+    }
+
+    /// Density of the spin-down electrons of an ISPIN = 2 file, `(rho - m) / 2`, see `spin_up`.
+    pub fn spin_down(&self) -> Result<Self> {
+        self._spin_channel(-1.0, "spin down")
+    }
+
+    fn _spin_channel(&self, sign: f64, name: &str) -> Result<Self> {
+        let mag = match self.chgdiff.as_slice() {
+            [mag] => mag,
+            [] => return Err(ChgError::MissingComponent(Component::Diff(0))),
+            diff => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                "{} diff components, spin channels need the one of an ISPIN = 2 file", diff.len())).into()),
+        };
+        if mag.shape() != self.chg.shape() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                "magnetization of shape {:?} for a density of {:?}", mag.shape(), self.chg.shape())).into());
+        }
+        // the total density is kept divided by the volume, the diff components are kept as read
+        let volume = self.pos.scaled_volume();
+        let mut chg = Array3::<T>::zeros(self.chg.raw_dim());
+        Zip::from(&mut chg).and(&self.chg).and(mag).apply(|out, &rho, &m| {
+            *out = T::from_f64((rho.to_f64() + sign * m.to_f64() / volume) / 2.0);
+        });
+        let mut channel = ChgBase::from_builder(chg, vec![], self.pos.clone());
+        channel.axis_scales = self.axis_scales;
+        channel.provenance = self.provenance.clone();
+        channel.provenance.record(name);
+        Ok(channel)
+    }
+
+    /// Write the spin-up and spin-down densities to `<prefix>_up` and `<prefix>_down`, e.g.
+    /// `PARCHG_up` and `PARCHG_down` for `PARCHG`, see `spin_up`.
+    pub fn write_spin_channels(&self, prefix: &(impl AsRef<Path> + ?Sized), chgtype: ChgType) -> Result<()> {
+        self.write_spin_channels_with(prefix, chgtype, &WriteOptions::default())
+    }
+
+    /// Write the spin-up and spin-down densities with options, see `write_spin_channels`.
+    pub fn write_spin_channels_with(&self, prefix: &(impl AsRef<Path> + ?Sized), chgtype: ChgType,
+                                    opts: &WriteOptions) -> Result<()> {
+        let prefix = prefix.as_ref();
+        let name = prefix.file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "prefix has no file name"))?;
+        for (channel, suffix) in [(self.spin_up()?, "_up"), (self.spin_down()?, "_down")] {
+            let mut name = name.to_owned();
+            name.push(suffix);
+            channel.write_file_with(&prefix.with_file_name(name), chgtype, opts)?;
+        }
         Ok(())
     }
 
     pub fn get_poscar(&self) -> &Poscar             { &self.pos }
     pub fn get_mut_poscar(&mut self) -> &mut Poscar { &mut self.pos}
 
-    pub fn get_total_chg(&self) -> &Array3<f64>     { &self.chg }
-    pub fn get_mut_total_chg(&mut self) -> &mut Array3<f64> { &mut self.chg }
+    /// Per-axis scales of the scaling factor line, `None` if there is a single scale.
+    ///
+    /// The scales are already applied to `get_poscar()`, they only decide how the header is
+    /// written: the lattice vectors are divided by them and they are written back on the second
+    /// line.
+    pub fn get_axis_scales(&self) -> Option<[f64; 3]> { self.axis_scales }
+    pub fn get_mut_axis_scales(&mut self) -> &mut Option<[f64; 3]> { &mut self.axis_scales }
 
-    pub fn get_diff_chg(&self) -> &Vec<Array3<f64>> { &self.chgdiff }
-    pub fn get_mut_diff_chg(&mut self) -> &mut Vec<Array3<f64>> { &mut self.chgdiff }
+    /// Layout of the file this was read from, `ChgType::Parchg` for `from_builder`.
+    ///
+    /// Pass it to `write_file` to write the data back in the same layout.
+    pub fn get_chgtype(&self) -> ChgType            { self.chgtype }
+
+    /// Files this was read from and the operations recorded on it, see
+    /// [`Provenance`](struct.Provenance.html).
+    ///
+    /// `from_file` takes the history from the sidecar of the file read if there is one and adds
+    /// the file itself, which it does without a sidecar only if `ReadOptions::provenance` is
+    /// set. Both need the `json` feature. Methods that derive new data, e.g. `spin_up`, record
+    /// what they did, changes made to the grids through `get_mut_total_chg` and the like are
+    /// recorded with `get_mut_provenance().record(..)`.
+    pub fn get_provenance(&self) -> &Provenance     { &self.provenance }
+    pub fn get_mut_provenance(&mut self) -> &mut Provenance { &mut self.provenance }
+
+    pub fn get_total_chg(&self) -> &Array3<T>       { &self.chg }
+    pub fn get_mut_total_chg(&mut self) -> &mut Array3<T> { &mut self.chg }
+
+    pub fn get_diff_chg(&self) -> &Vec<Array3<T>>   { &self.chgdiff }
+    pub fn get_mut_diff_chg(&mut self) -> &mut Vec<Array3<T>> { &mut self.chgdiff }
 
     /// Return the immutable reference of the shape of the grid.
     pub fn get_ngrid(&self) -> &[usize; 3]          { &self.ngrid }
@@ -283,19 +580,909 @@ impl ChgBase {
     /// Note: don't forget to **update the shpae** of if any `reshape` like operations are applied.
     pub fn get_mut_ngrid(&mut self) -> &mut [usize; 3] { &mut self.ngrid }
 
+    /// Augmentation occupancies of the total density as raw text, `None` if there are none,
+    /// e.g. in a CHG or PARCHG, or for `from_builder`.
     pub fn get_total_aug(&self) -> Option<&String> {
         if let Some(aug) = &self.aug {
             Some(aug)
         } else { None }
     }
     pub fn get_diff_aug(&self) -> &Vec<String>      { &self.augdiff }
+
+    /// Per-ion magnetic moments after the grid and augmentation occupancies of each component,
+    /// total density first, as raw text, empty if there are none. VASP writes them in front of
+    /// the magnetization of a spin-polarized file. Empty for `from_builder`.
+    pub fn get_magmom(&self) -> &Vec<String>        { &self.magmom }
+}
+
+impl ChgBase {
+    /// Write the POSCAR part of the header, with the per-axis scales on the scaling factor line
+    /// if there are any, and `title` instead of the comment of `pos` if given.
+    pub(crate) fn _write_header(file: &mut impl Write, pos: &Poscar, axis_scales: Option<[f64; 3]>,
+                                title: Option<&str>) -> Result<()> {
+        let text = match axis_scales {
+            Some(scales) => format!("{:>9.6}", ChgBase::_scale_axes(pos, scales, |v, s| v / s)?),
+            None => format!("{:>9.6}", pos),
+        };
+        let mut lines = text.split_inclusive('\n');
+        let comment = lines.next().unwrap_or_default();
+        match title {
+            Some(title) => writeln!(file, "{}", title)?,
+            None => write!(file, "{}", comment)?,
+        }
+        if let Some(scales) = axis_scales {
+            lines.next();
+            writeln!(file, "  {:>9.6} {:>9.6} {:>9.6}", scales[0], scales[1], scales[2])?;
+        }
+        lines.try_for_each(|line| write!(file, "{}", line))?;
+        Ok(writeln!(file)?)
+    }
+
+    /// Write the `NGX NGY NGZ` line in front of a grid.
+    pub(crate) fn _write_ngrid(file: &mut impl Write, ngrid: &[usize]) -> io::Result<()> {
+        ngrid.iter()
+            .try_for_each(|n| write!(file, " {:>4}", n))?;
+        writeln!(file)
+    }
+
+    /// Augmentation occupancies of zero, `lmmax[i]` of them for the `i`-th of `nsites` atoms,
+    /// in VASP's `(5E15.7)` layout.
+    pub(crate) fn _zero_aug(lmmax: &[usize], nsites: usize) -> Result<String> {
+        if lmmax.len() != nsites {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                "{} LMMAX given for {} atoms", lmmax.len(), nsites)).into());
+        }
+        let mut text = String::new();
+        for (i, &n) in lmmax.iter().enumerate() {
+            writeln!(text, "augmentation occupancies {:>3} {:>3}", i + 1, n)
+                .expect("writing to a String cannot fail");
+            for row in 0 .. n.div_ceil(5) {
+                for _ in row * 5 .. n.min(row * 5 + 5) {
+                    fortran::write_real(&mut text, 0.0, 7, 15);
+                }
+                text.push('\n');
+            }
+        }
+        Ok(text)
+    }
+
+    /// Read volumetric data from existing file.
+    ///
+    /// Usually you can use &str as path(, or &std::path::Path, which is my preference).
+    ///
+    /// Compressed files are detected by their magic bytes and decompressed on the fly, each codec
+    /// requires its cargo feature: `gzip` (enabled by default), `xz`, `zstd` and `bzip2`.
+    pub fn from_file(path: &(impl AsRef<Path> + ?Sized)) -> Result<Self> {
+        Self::from_file_with(path, &ReadOptions::default())
+    }
+
+    /// Read volumetric data from existing file with options, e.g. the policy for `****` fields.
+    pub fn from_file_with(path: &(impl AsRef<Path> + ?Sized), opts: &ReadOptions) -> Result<Self> {
+        Self::from_file_as(path, opts)
+    }
+
+    /// Read volumetric data from reading buffer.
+    ///
+    /// Only `BufRead` is required, so non-seekable streams such as `stdin.lock()`, pipes or
+    /// decompressors can be read directly. Reading starts at the current position of the stream.
+    ///
+    /// Parse failures are reported with the line number, the byte offset and the section of
+    /// the file being read, see [`ChgError`](enum.ChgError.html).
+    ///
+    /// See the unit tests in this source file for detailed usage.
+    pub fn from_reader(file: &mut impl BufRead) -> Result<Self> {
+        Self::from_reader_with(file, &ReadOptions::default())
+    }
+
+    /// Read volumetric data from reading buffer with options.
+    ///
+    /// Numbers glued together by Fortran are always split, fields filled with `*` are handled
+    /// according to `opts.overflow`.
+    pub fn from_reader_with(file: &mut impl BufRead, opts: &ReadOptions) -> Result<Self> {
+        Self::from_reader_as(file, opts)
+    }
+
+    /// Read volumetric data from bytes in memory, without copying the lines.
+    ///
+    /// Compressed data is recognized and decompressed like in `from_file`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Self::from_bytes_with(bytes, &ReadOptions::default())
+    }
+
+    /// Read volumetric data from bytes in memory with options.
+    pub fn from_bytes_with(bytes: &[u8], opts: &ReadOptions) -> Result<Self> {
+        Self::from_bytes_as(bytes, opts)
+    }
+
+    /// Read volumetric data from a memory-mapped file.
+    ///
+    /// The numbers are parsed straight from the mapped pages into the grids, so besides the
+    /// page cache, peak memory is about the size of the arrays, while `from_file` needs an
+    /// extra copy of the grid being read. With the `rayon` feature enabled a grid-sized buffer
+    /// is still needed for the parallel parser. Compressed files cannot be mapped and are read
+    /// like `from_file` does.
+    ///
+    /// The file must not be modified by other processes while it is read.
+    ///
+    /// Requires the `mmap` feature.
+    #[cfg(feature = "mmap")]
+    pub fn from_file_mmap(path: &(impl AsRef<Path> + ?Sized)) -> Result<Self> {
+        Self::from_file_mmap_with(path, &ReadOptions::default())
+    }
+
+    /// Read volumetric data from a memory-mapped file with options.
+    #[cfg(feature = "mmap")]
+    pub fn from_file_mmap_with(path: &(impl AsRef<Path> + ?Sized), opts: &ReadOptions) -> Result<Self> {
+        Self::from_file_mmap_as(path, opts)
+    }
+
+    /// Read a whole file, `path` is where it comes from, if known.
+    fn _read_all<T: ChgFloat>(file: &mut LineReader<impl LineSource>, opts: &ReadOptions,
+                              path: Option<&Path>) -> Result<ChgBase<T>> {
+        if opts.lossless {
+            file.record();
+        }
+        let species = species::resolve(opts, path)?;
+        let (pos, axis_scales) = Self::_read_header(file, species.as_deref())?;
+        let start = file.offset() as usize;
+        let (chg, row) = Self::_read_chg(file, Section::TotalDensity, opts, pos.scaled_volume())?;
+        let grid = start .. file.offset() as usize;
+        let (aug, magmom) = Self::_read_raw_aug(file, None, opts)?;
+        let chgtype = ChgType::_detect(row, !aug.is_empty());
+        let aug = Some(aug).filter(|aug| !aug.is_empty());
+        let mut magmom = vec![magmom];
+        let mut grids = vec![grid];
+        let (chgdiff, augdiff) = Self::_read_optional_parts(file, opts, &mut grids, &mut magmom)?;
+        let ngrid = chg.shape().to_owned();
+        let ngrid = [ngrid[0], ngrid[1], ngrid[2]];
+        let source = file.take_recorded().map(|text| {
+            let keys = Some(lossless::grid_key(&chg, pos.scaled_volume())).into_iter()
+                .chain(chgdiff.iter().map(|chg| lossless::grid_key(chg, 1.0)))
+                .collect();
+            // the text has other species than `pos` if they are overridden
+            let header = Some(lossless::header_key(&pos, axis_scales)).filter(|_| species.is_none());
+            Source::new(text, header, &grids, keys)
+        });
+        Ok(
+            ChgBase { pos, chg, aug, chgdiff, augdiff, magmom, ngrid, axis_scales, chgtype, source,
+                      provenance: Provenance::default() }
+        )
+    }
+
+    /// Read the diff components, the byte ranges of their grids are pushed to `grids` and the
+    /// magnetic moments after them to `magmom`.
+    fn _read_optional_parts<T: ChgFloat>(file: &mut LineReader<impl LineSource>, opts: &ReadOptions,
+                                         grids: &mut Vec<Range<usize>>, magmom: &mut Vec<String>)
+        -> Result<(Vec<Array3<T>>, Vec<String>)> {
+        let mut chgdiff = vec![];
+        let mut augdiff = vec![];
+
+        while Self::_skip_blank(file)? {
+            let component = chgdiff.len();
+            let start = file.offset() as usize;
+            chgdiff.push(Self::_read_chg(file, Section::DiffDensity { component }, opts, 1.0)?.0);
+            grids.push(start .. file.offset() as usize);
+            let (aug, moments) = Self::_read_raw_aug(file, Some(component), opts)?;
+            augdiff.push(aug);
+            magmom.push(moments);
+        }
+        Ok((chgdiff, augdiff))
+    }
+
+    /// Skip blank lines in front of the next section, returns `false` if nothing but EOF is left.
+    fn _skip_blank(file: &mut LineReader<impl LineSource>) -> Result<bool> {
+        while file.advance()? {
+            if !file.current().trim().is_empty() {
+                file.unread();
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Read the POSCAR part of the header.
+    ///
+    /// The number of lines is worked out from the ion counts instead of looking for the blank
+    /// line in front of the grid, which may hold whitespace or be missing altogether. Line
+    /// endings are normalized to `\n` for `vasp_poscar`. The species line is replaced by, or
+    /// inserted from, `species` if given.
+    fn _read_poscar(file: &mut LineReader<impl LineSource>, species: Option<&[String]>) -> Result<Poscar> {
+        Ok(Self::_read_header(file, species)?.0)
+    }
+
+    /// Read the POSCAR part of the header, also returns the per-axis scales if the scaling
+    /// factor line holds three of them.
+    ///
+    /// `vasp_poscar` takes a single scale only, three scales are applied to the Cartesian
+    /// components of the lattice vectors and of Cartesian positions, as VASP does, and the
+    /// returned `Poscar` has a scale of 1.
+    fn _read_header(file: &mut LineReader<impl LineSource>, species: Option<&[String]>)
+        -> Result<(Poscar, Option<[f64; 3]>)> {
+        let mut buf = String::new();
+        Self::_header_line(file, &mut buf, "comment line")?;
+        let scale_at = buf.len();
+        let line = Self::_header_line(file, &mut buf, "scaling factor")?;
+        let axis_scales = Self::_parse_axis_scales(&line, file.location(Section::Header, 0))?;
+        if axis_scales.is_some() {
+            buf.truncate(scale_at);
+            buf.push_str("1.0\n");
+        }
+        for what in &["lattice vectors", "lattice vectors", "lattice vectors"] {
+            Self::_header_line(file, &mut buf, what)?;
+        }
+
+        // species names are optional, VASP 4 goes straight to the ion counts
+        let species_at = buf.len();
+        let mut line = Self::_header_line(file, &mut buf, "ion counts")?;
+        let counts = match Self::_parse_counts(&line) {
+            Some(counts) => counts,
+            None => {
+                line = Self::_header_line(file, &mut buf, "ion counts")?;
+                Self::_parse_counts(&line).ok_or_else(|| ChgError::Header {
+                    msg: format!("invalid ion counts {:?}", line.trim()),
+                    at: file.location(Section::Header, 0),
+                })?
+            },
+        };
+        if let Some(species) = species {
+            if species.len() != counts.len() {
+                return Err(ChgError::Header {
+                    msg: format!("{} species names given for {} ion counts", species.len(), counts.len()),
+                    at: file.location(Section::Header, 0),
+                });
+            }
+            buf.truncate(species_at);
+            buf.push_str(&format!("   {}\n", species.join("   ")));
+            buf.push_str(&line);
+            buf.push('\n');
+        }
+        let nions: usize = counts.iter().sum();
+
+        let line = Self::_header_line(file, &mut buf, "coordinate system")?;
+        if line.trim_start().starts_with(&['s', 'S'][..]) {
+            Self::_header_line(file, &mut buf, "coordinate system")?;
+        }
+        for _ in 0 .. nions {
+            Self::_header_line(file, &mut buf, &format!("{} ion positions", nions))?;
+        }
+
+        Self::_skip_blank(file)?;
+        let pos = Poscar::from_reader(
+            io::Cursor::new(buf.into_bytes())
+        )?;
+        match axis_scales {
+            Some(scales) => Ok((Self::_scale_axes(&pos, scales, |v, s| v * s)?, axis_scales)),
+            None => Ok((pos, None)),
+        }
+    }
+
+    /// Parse the scaling factor line, returns the scales if there are three of them, a single
+    /// scale, negative for the volume, is left to `vasp_poscar`.
+    fn _parse_axis_scales(line: &str, at: Location) -> Result<Option<[f64; 3]>> {
+        let tokens = line.split_ascii_whitespace().collect::<Vec<_>>();
+        if tokens.len() != 3 {
+            return Ok(None);
+        }
+        let bad_line = || ChgError::Header {
+            msg: format!("invalid scaling factors {:?}, three must all be positive", line.trim()),
+            at,
+        };
+        let mut scales = [0.0; 3];
+        for (scale, token) in scales.iter_mut().zip(tokens) {
+            *scale = fortran::parse_real(token).filter(|&v| v > 0.0).ok_or_else(bad_line)?;
+        }
+        Ok(Some(scales))
+    }
+
+    /// Apply `f(value, scale)` to the Cartesian components of the scaled lattice vectors, and of
+    /// the positions if they are Cartesian. The returned `Poscar` has a scale of 1.
+    fn _scale_axes(pos: &Poscar, scales: [f64; 3], f: impl Fn(f64, f64) -> f64) -> Result<Poscar> {
+        let scale = |mut v: [f64; 3]| {
+            v.iter_mut().zip(&scales).for_each(|(v, &s)| *v = f(*v, s));
+            v
+        };
+        let lattice_vectors = pos.scaled_lattice_vectors();
+        let positions = match pos.scaled_positions() {
+            Coords::Cart(positions) => Coords::Cart(positions.iter().copied().map(scale).collect()),
+            Coords::Frac(positions) => Coords::Frac(positions.into_owned()),
+        };
+        let mut raw = pos.clone().into_raw();
+        raw.scale = ScaleLine::Factor(1.0);
+        raw.lattice_vectors = [scale(lattice_vectors[0]), scale(lattice_vectors[1]), scale(lattice_vectors[2])];
+        raw.positions = positions;
+        raw.validate().map_err(|e| ChgError::Poscar(e.into()))
+    }
+
+    /// Append the next line of the header to `buf` with its line ending normalized, `what` tells
+    /// what the line should be if the file ends early.
+    fn _header_line(file: &mut LineReader<impl LineSource>, buf: &mut String, what: &str) -> Result<String> {
+        if !file.advance()? {
+            return Err(ChgError::Header {
+                msg: format!("end of file reached, expected {}", what),
+                at: file.location(Section::Header, 0),
+            });
+        }
+        let line = file.current().trim_end_matches(&['\r', '\n'][..]);
+        buf.push_str(line);
+        buf.push('\n');
+        Ok(line.to_owned())
+    }
+
+    fn _parse_counts(line: &str) -> Option<Vec<usize>> {
+        let counts = line.split_ascii_whitespace()
+            .map(|t| t.parse::<usize>().ok())
+            .collect::<Option<Vec<_>>>()?;
+        if counts.is_empty() { None } else { Some(counts) }
+    }
+
+    fn _parse_ngrid(line: &str, at: Location) -> Result<[usize; 3]> {
+        let bad_line = || ChgError::BadGridLine { line: line.trim_end().to_owned(), at };
+        let ngrid = line.split_ascii_whitespace()
+            .map(|t| t.parse::<usize>().map_err(|_| bad_line()))
+            .collect::<Result<Vec<_>>>()?;
+        // the grid must fit in memory as `f64`s, which also rules out an overflowing product
+        let fits = |len: usize| len.checked_mul(std::mem::size_of::<f64>())
+            .is_some_and(|size| size <= isize::MAX as usize);
+        match ngrid[..] {
+            [nx, ny, nz] => match nx.checked_mul(ny).and_then(|n| n.checked_mul(nz)) {
+                Some(len) if len > 0 && fits(len) => Ok([nx, ny, nz]),
+                _ => Err(bad_line()),
+            },
+            _ => Err(bad_line()),
+        }
+    }
+
+    /// Buffer for `len` values of a grid of `ngrid`, an error instead of an abort if the
+    /// allocation fails.
+    fn _grid_buffer<U>(ngrid: [usize; 3], len: usize) -> Result<Vec<U>> {
+        let mut buf = Vec::new();
+        buf.try_reserve_exact(len).map_err(|_| io::Error::new(io::ErrorKind::OutOfMemory,
+            format!("cannot allocate a grid of {} x {} x {}", ngrid[0], ngrid[1], ngrid[2])))?;
+        Ok(buf)
+    }
+
+    /// Read a grid, the values are divided by `volume` and stored as `T`.
+    #[cfg(not(feature = "rayon"))]
+    fn _read_chg<T: ChgFloat>(file: &mut LineReader<impl LineSource>, section: Section,
+                              opts: &ReadOptions, volume: f64) -> Result<(Array3<T>, usize)> {
+        let ngrid = Self::_read_ngrid(file, section)?;
+        let [nx, ny, nz] = ngrid;
+        let len = nx * ny * nz;
+        let mut buf = ChgBase::_grid_buffer(ngrid, len)?;
+        buf.resize(len, T::zero());
+        let mut chg = Array3::from_shape_vec((nx, ny, nz), buf).expect("length of buffer is the grid size");
+        let out = chg.as_slice_mut().expect("new array is in standard layout");
+        // values come x fastest, scatter them right into `[x, y, z]`, skipping trailing extras
+        let mut i = 0;
+        let sink = |v: f64| {
+            if i < len {
+                let (x, yz) = (i % nx, i / nx);
+                out[(x * ny + yz % ny) * nz + yz / ny] = T::from_f64(v / volume);
+            }
+            i += 1;
+        };
+        let row = Self::_read_values(file, section, len, opts, Some(sink))?;
+        Ok((chg, row))
+    }
+
+    /// Read a grid, the values are divided by `volume` and stored as `T`.
+    #[cfg(feature = "rayon")]
+    fn _read_chg<T: ChgFloat>(file: &mut LineReader<impl LineSource>, section: Section,
+                              opts: &ReadOptions, volume: f64) -> Result<(Array3<T>, usize)> {
+        let ngrid = Self::_read_ngrid(file, section)?;
+        let len = ngrid.iter().product();
+        let mut buf = ChgBase::_grid_buffer(ngrid, len)?;
+        let row = Self::_read_values_par(file, section, len, opts, &mut buf, volume,
+                                         (PAR_BATCH_LINES, PAR_CHUNK_LINES))?;
+        Ok((Self::_to_standard_layout(ngrid, buf)?, row))
+    }
+
+    /// Reshape values in file order, x fastest, to an array indexed by `[x, y, z]`, one `x` plane
+    /// per task.
+    #[cfg(feature = "rayon")]
+    fn _to_standard_layout<T: ChgFloat>(ngrid: [usize; 3], buf: Vec<T>) -> Result<Array3<T>> {
+        use rayon::prelude::*;
+
+        let [nx, ny, nz] = ngrid;
+        let mut out = ChgBase::_grid_buffer(ngrid, buf.len())?;
+        out.resize(buf.len(), T::zero());
+        out.par_chunks_mut(ny * nz).enumerate().for_each(|(x, plane)| {
+            for (yz, v) in plane.iter_mut().enumerate() {
+                let (y, z) = (yz / nz, yz % nz);
+                *v = buf[x + nx * (y + ny * z)];
+            }
+        });
+        Ok(Array3::from_shape_vec((nx, ny, nz), out).expect("length of buffer already checked"))
+    }
+
+    /// Skip over a grid without converting the numbers, returns the shape of the grid and the
+    /// number of values in its first row.
+    fn _skip_chg(file: &mut LineReader<impl LineSource>, section: Section, opts: &ReadOptions)
+        -> Result<([usize; 3], usize)> {
+        let ngrid = Self::_read_ngrid(file, section)?;
+        let row = Self::_read_values(file, section, ngrid.iter().product(), opts, None::<fn(f64)>)?;
+        Ok((ngrid, row))
+    }
+
+    fn _read_ngrid(file: &mut LineReader<impl LineSource>, section: Section) -> Result<[usize; 3]> {
+        file.advance()?;
+        Self::_parse_ngrid(file.current(), file.location(section, 0))
+    }
+
+    /// Walk over `len` values of a grid, pass them to `sink` if given, otherwise only count them.
+    /// A few more values may be passed if the last line is longer than needed.
+    fn _read_values(file: &mut LineReader<impl LineSource>, section: Section, len: usize,
+                    opts: &ReadOptions, mut sink: Option<impl FnMut(f64)>) -> Result<usize> {
+        let mut count = 0;
+        let mut row = None;
+        let mut width: Option<usize> = None;
+        while count < len {
+            if !file.advance()? {
+                return Err(ChgError::ShortGrid {
+                    expected: len,
+                    found: count,
+                    at: file.location(section, 0),
+                });
+            }
+            let line = file.current();
+            let at = file.location(section, 0);
+            width = width.or_else(|| Self::_field_width(line));
+            let nfield = Self::_grid_line(line, width, at, opts, sink.as_mut())?;
+            if row.is_none() && nfield > 0 {
+                row = Some(nfield);
+            }
+            count += nfield;
+        }
+        Ok(row.unwrap_or(0))
+    }
+
+    /// Field width of a grid line, needed to count the fields in a run of `*`. It is taken from
+    /// the first number, which spans from the end of the token in front of it, `None` if the
+    /// line has no number.
+    fn _field_width(line: &str) -> Option<usize> {
+        let mut end = 0;
+        for (col, token) in fortran::tokens(line) {
+            match token {
+                Token::Number(t) => return Some(col + t.len() - end),
+                Token::Overflow(t) => end = col + t.len(),
+            }
+        }
+        None
+    }
+
+    /// Walk over the fields of one line of a grid, passing the values to `sink` if given. `at`
+    /// is the location of the line and `width` the field width learned so far. Returns the
+    /// number of fields.
+    fn _grid_line(line: &str, width: Option<usize>, at: Location, opts: &ReadOptions,
+                  mut sink: Option<impl FnMut(f64)>) -> Result<usize> {
+        let at = |col: usize| Location { offset: at.offset + col as u64, ..at };
+        let mut nfield = 0;
+        for (col, token) in fortran::tokens(line) {
+            match token {
+                Token::Number(t) => {
+                    if let Some(sink) = sink.as_mut() {
+                        let v = fortran::parse_real(t)
+                            .ok_or_else(|| ChgError::InvalidNumber {
+                                token: t.to_owned(),
+                                at: at(col),
+                            })?;
+                        sink(v);
+                    }
+                    nfield += 1;
+                },
+                Token::Overflow(t) => {
+                    let n = width.map_or(1, |w| ((t.len() + w / 2) / w).max(1));
+                    if let Some(sink) = sink.as_mut() {
+                        let v = Self::_overflow_value(t, at(col), opts)?;
+                        (0 .. n).for_each(|_| sink(v));
+                    }
+                    nfield += n;
+                },
+            }
+        }
+        Ok(nfield)
+    }
+
+    /// Parallel version of `_read_values`.
+    ///
+    /// Up to `batch` lines are collected and counted serially, which is cheap, then the batch is
+    /// split into chunks of `chunk` lines that are parsed in parallel right into their slice of
+    /// `buf`, divided by `volume` and stored as `T`. Each value goes through the same
+    /// `parse_real` and conversion as the serial path, so the result is bit-identical, and the
+    /// first error in file order is returned.
+    #[cfg(feature = "rayon")]
+    fn _read_values_par<T: ChgFloat>(file: &mut LineReader<impl LineSource>, section: Section,
+                                     len: usize, opts: &ReadOptions, buf: &mut Vec<T>, volume: f64,
+                                     (batch, chunk): (usize, usize)) -> Result<usize> {
+        use rayon::prelude::*;
+
+        struct Line {
+            text:   std::ops::Range<usize>, // position in the text of the batch
+            start:  usize,                  // index of the first value, relative to the batch
+            width:  Option<usize>,
+            at:     Location,
+        }
+
+        let mut text = String::new();
+        let mut lines = Vec::<Line>::new();
+        let mut count = 0;
+        let mut row = None;
+        let mut width: Option<usize> = None;
+        while count < len {
+            text.clear();
+            lines.clear();
+            let first = count;
+            while count < len && lines.len() < batch {
+                if !file.advance()? {
+                    return Err(ChgError::ShortGrid {
+                        expected: len,
+                        found: count,
+                        at: file.location(section, 0),
+                    });
+                }
+                let line = file.current();
+                let at = file.location(section, 0);
+                width = width.or_else(|| Self::_field_width(line));
+                let nfield = Self::_grid_line(line, width, at, opts, None::<fn(f64)>)?;
+                lines.push(Line { text: text.len() .. text.len() + line.len(), start: count - first, width, at });
+                text.push_str(line);
+                if row.is_none() && nfield > 0 {
+                    row = Some(nfield);
+                }
+                count += nfield;
+            }
+
+            buf.resize(count, T::zero());
+            let mut rest = &mut buf[first ..];
+            let mut jobs = Vec::with_capacity(lines.len() / chunk + 1);
+            for (i, part) in lines.chunks(chunk).enumerate() {
+                let end = lines.get((i + 1) * chunk).map_or(count - first, |l| l.start);
+                let (head, tail) = std::mem::take(&mut rest).split_at_mut(end - part[0].start);
+                jobs.push((part, head));
+                rest = tail;
+            }
+            let text = text.as_str();
+            let results: Vec<Result<()>> = jobs.into_par_iter().map(|(lines, out)| {
+                let mut values = out.iter_mut();
+                for line in lines {
+                    let sink = |v| *values.next().expect("fields already counted") = T::from_f64(v / volume);
+                    Self::_grid_line(&text[line.text.clone()], line.width, line.at, opts, Some(sink))?;
+                }
+                Ok(())
+            }).collect();
+            results.into_iter().collect::<Result<()>>()?;
+        }
+        buf.truncate(len);
+        Ok(row.unwrap_or(0))
+    }
+
+    fn _overflow_value(token: &str, at: Location, opts: &ReadOptions) -> Result<f64> {
+        match opts.overflow {
+            OverflowPolicy::Error => Err(ChgError::Overflow { token: token.to_owned(), at }),
+            OverflowPolicy::NaN => Ok(f64::NAN),
+            OverflowPolicy::Clamp(v) => Ok(v),
+        }
+    }
+
+    /// Read the augmentation occupancies following a grid and the magnetic moments after them as
+    /// raw text, `component` is `None` for the total density.
+    fn _read_raw_aug(file: &mut LineReader<impl LineSource>, component: Option<usize>,
+                     opts: &ReadOptions) -> Result<(String, String)> {
+        let mut raw_aug = String::new();
+        let mut magmom = String::new();
+        let mut split = AugSplit::default();
+        let mut block = 0;
+        while file.advance()? {
+            let line = file.current();
+            if Self::_is_ngrid_line(line) {     // take until " NGXF NGYF NGZF"
+                file.unread();
+                break;
+            }
+
+            if line.starts_with("aug") {
+                block = line.split_ascii_whitespace().nth(2)
+                    .and_then(|t| t.parse::<usize>().ok())
+                    .unwrap_or(block + 1);
+            } else {
+                let section = match component {
+                    None => Section::TotalAugmentation { block },
+                    Some(component) => Section::DiffAugmentation { component, block },
+                };
+                for (col, token) in fortran::tokens(line) {
+                    match token {
+                        Token::Number(t) => if fortran::parse_real(t).is_none() {
+                            return Err(ChgError::InvalidNumber {
+                                token: t.to_owned(),
+                                at: file.location(section, col),
+                            });
+                        },
+                        Token::Overflow(t) => {
+                            Self::_overflow_value(t, file.location(section, col), opts)?;
+                        },
+                    }
+                }
+            }
+            let text = if split.is_aug(line) { &mut raw_aug } else { &mut magmom };
+            text.push_str(line.trim_end_matches(&['\r', '\n'][..]));
+            text.push('\n');
+        }
+        // blank lines after a grid, e.g. at the end of a PARCHG, are no occupancies
+        if raw_aug.trim().is_empty() {
+            raw_aug.clear();
+        }
+        Ok((raw_aug, magmom))
+    }
+
+    /// Skip over the augmentation occupancies following a grid and the magnetic moments after
+    /// them, returns where each begins, or `None` if there are none.
+    fn _skip_raw_aug(file: &mut LineReader<impl LineSource>)
+        -> Result<(Option<SectionStart>, Option<SectionStart>)> {
+        let (mut aug, mut magmom) = (None, None);
+        let mut split = AugSplit::default();
+        while file.advance()? {
+            let line = file.current();
+            if Self::_is_ngrid_line(line) {
+                file.unread();
+                break;
+            }
+            let start = if split.is_aug(line) { &mut aug } else { &mut magmom };
+            if start.is_none() && !line.trim().is_empty() {
+                *start = Some(file.section_start());
+            }
+        }
+        Ok((aug, magmom))
+    }
+
+    fn _is_ngrid_line(line: &str) -> bool {
+        let mut n = 0;
+        for t in line.split_ascii_whitespace() {
+            if n == 3 || !t.bytes().all(|c| c.is_ascii_digit()) {
+                return false;
+            }
+            n += 1;
+        }
+        n == 3
+    }
+
+    /// Scan the header and the layout of a file without converting the numbers in the grids.
+    ///
+    /// This is much faster than `from_file` when only the structure, the grid size or the kind
+    /// of the file (spin-polarized, non-collinear) is needed. Compressed files are supported as
+    /// in `from_file`, the offsets then refer to the decompressed text.
+    ///
+    /// ```no_run
+    /// use vaspchg_rs::ChgBase;
+    ///
+    /// let meta = ChgBase::scan_metadata("CHGCAR").unwrap();
+    /// println!("{:?} {}", meta.get_ngrid(), meta.is_spin_polarized());
+    /// ```
+    pub fn scan_metadata(path: &(impl AsRef<Path> + ?Sized)) -> Result<ChgMeta> {
+        let file = File::open(path)?;
+        let file = compress::decompress(BufReader::new(file))?;
+        Self::_scan(&mut LineReader::new(file), None)
+    }
+
+    pub(crate) fn _scan(file: &mut LineReader<impl LineSource>, species: Option<&[String]>)
+        -> Result<ChgMeta> {
+        let opts = ReadOptions::default();
+        let (pos, axis_scales) = Self::_read_header(file, species)?;
+
+        let mut ngrid = [0; 3];
+        let mut row = 0;
+        let mut sections = vec![];
+        while Self::_skip_blank(file)? {
+            let section = match sections.len() {
+                0 => Section::TotalDensity,
+                k => Section::DiffDensity { component: k - 1 },
+            };
+            file.advance()?;
+            let grid = file.section_start();
+            file.unread();
+            let (shape, first_row) = Self::_skip_chg(file, section, &opts)?;
+            if sections.is_empty() {
+                ngrid = shape;
+                row = first_row;
+            }
+            let (aug, magmom) = Self::_skip_raw_aug(file)?;
+            sections.push(ComponentOffsets { grid, aug, magmom });
+        }
+
+        if sections.is_empty() {
+            file.advance()?;
+            return Err(ChgError::BadGridLine {
+                line: String::new(),
+                at: file.location(Section::TotalDensity, 0),
+            });
+        }
+        let chgtype = ChgType::_detect(row, sections[0].aug.is_some());
+        Ok(ChgMeta::new(pos, axis_scales, chgtype, ngrid, sections))
+    }
+
+    /// Read the grid of a single density component, skipping over everything in front of it
+    /// without converting the numbers.
+    ///
+    /// The total density is divided by the cell volume as in `from_file`, diff components are
+    /// returned as they are in the file. Compressed files are supported.
+    ///
+    /// ```no_run
+    /// use vaspchg_rs::{ChgBase, Component};
+    ///
+    /// // m_z of a non-collinear CHGCAR
+    /// let mz = ChgBase::read_grid("CHGCAR", Component::Diff(2)).unwrap();
+    /// ```
+    pub fn read_grid(path: &(impl AsRef<Path> + ?Sized), component: Component) -> Result<Array3<f64>> {
+        let file = File::open(path)?;
+        let mut file = LineReader::new(compress::decompress(BufReader::new(file))?);
+        let pos = Self::_read_poscar(&mut file, None)?;
+        Self::_skip_to_component(&mut file, component)?;
+        Self::_read_component_chg(&mut file, &pos, component, &ReadOptions::default())
+    }
+
+    /// Read the augmentation occupancies of a single density component as raw text, `None` if
+    /// there are none, e.g. in a PARCHG.
+    pub fn read_aug(path: &(impl AsRef<Path> + ?Sized), component: Component) -> Result<Option<String>> {
+        let file = File::open(path)?;
+        let mut file = LineReader::new(compress::decompress(BufReader::new(file))?);
+        Self::_read_poscar(&mut file, None)?;
+        Self::_skip_to_component(&mut file, component)?;
+        Self::_skip_chg(&mut file, Self::_component_section(component), &ReadOptions::default())?;
+        let (aug, _) = Self::_read_raw_aug(&mut file, Self::_aug_component(component), &ReadOptions::default())?;
+        Ok(if aug.is_empty() { None } else { Some(aug) })
+    }
+
+    /// Read the grid of a single density component at the offset found by `scan_metadata`.
+    ///
+    /// Plain files are seeked to the grid directly, which makes repeated access to the
+    /// components of a big file cheap. Compressed files have to be decompressed up to the grid.
+    pub fn read_grid_at(path: &(impl AsRef<Path> + ?Sized), meta: &ChgMeta, component: Component)
+        -> Result<Array3<f64>> {
+        Self::_read_grid_from(File::open(path)?, meta, component, &ReadOptions::default())
+    }
+
+    /// Read the augmentation occupancies of a single density component at the offset found by
+    /// `scan_metadata`, `None` if there are none.
+    pub fn read_aug_at(path: &(impl AsRef<Path> + ?Sized), meta: &ChgMeta, component: Component)
+        -> Result<Option<String>> {
+        Ok(Self::_read_aug_from(File::open(path)?, meta, component, &ReadOptions::default())?.0)
+    }
+
+    pub(crate) fn _read_grid_from<T: ChgFloat>(file: impl Read + Seek, meta: &ChgMeta,
+                                               component: Component, opts: &ReadOptions)
+        -> Result<Array3<T>> {
+        let start = meta.get_component_offsets(component)
+            .ok_or(ChgError::MissingComponent(component))?.grid;
+        let mut file = Self::_open_at(file, start)?;
+        Self::_read_component_chg(&mut file, meta.get_poscar(), component, opts)
+    }
+
+    /// Read the augmentation occupancies of `component`, `None` if there are none, and the
+    /// magnetic moments after them.
+    pub(crate) fn _read_aug_from(file: impl Read + Seek, meta: &ChgMeta, component: Component,
+                                 opts: &ReadOptions) -> Result<(Option<String>, String)> {
+        let offsets = meta.get_component_offsets(component)
+            .ok_or(ChgError::MissingComponent(component))?;
+        match offsets.aug.or(offsets.magmom) {
+            Some(start) => {
+                let mut file = Self::_open_at(file, start)?;
+                let (aug, magmom) = Self::_read_raw_aug(&mut file, Self::_aug_component(component), opts)?;
+                Ok((Some(aug).filter(|aug| !aug.is_empty()), magmom))
+            },
+            None => Ok((None, String::new())),
+        }
+    }
+
+    /// Move `file` to `start`, seeking in plain files and skipping decompressed bytes otherwise.
+    fn _open_at<'a>(file: impl Read + Seek + 'a, start: SectionStart)
+        -> Result<LineReader<Buffered<Box<dyn BufRead + 'a>>>> {
+        let mut file = BufReader::new(file);
+        file.seek(SeekFrom::Start(0))?;
+        let mut file: Box<dyn BufRead> = if Codec::sniff(file.fill_buf()?) == Codec::Plain {
+            file.seek(SeekFrom::Start(start.offset))?;
+            Box::new(file)
+        } else {
+            let mut file = compress::decompress(file)?;
+            io::copy(&mut (&mut file).take(start.offset), &mut io::sink())?;
+            file
+        };
+        if file.fill_buf()?.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                      "section offset beyond the end of file").into());
+        }
+        Ok(LineReader::starting_at(file, start.offset, start.line))
+    }
+
+    /// Skip the grids and augmentation occupancies in front of `component`.
+    fn _skip_to_component(file: &mut LineReader<impl LineSource>, component: Component) -> Result<()> {
+        let opts = ReadOptions::default();
+        for k in 0 .. component.index() {
+            let section = match k {
+                0 => Section::TotalDensity,
+                k => Section::DiffDensity { component: k - 1 },
+            };
+            if !Self::_skip_blank(file)? {
+                return Err(ChgError::MissingComponent(component));
+            }
+            Self::_skip_chg(file, section, &opts)?;
+            Self::_skip_raw_aug(file)?;
+        }
+        if !Self::_skip_blank(file)? {
+            return Err(ChgError::MissingComponent(component));
+        }
+        Ok(())
+    }
+
+    fn _read_component_chg<T: ChgFloat>(file: &mut LineReader<impl LineSource>, pos: &Poscar,
+                                        component: Component, opts: &ReadOptions) -> Result<Array3<T>> {
+        let volume = match component {
+            Component::Total => pos.scaled_volume(),
+            Component::Diff(_) => 1.0,
+        };
+        Ok(Self::_read_chg(file, Self::_component_section(component), opts, volume)?.0)
+    }
+
+    fn _component_section(component: Component) -> Section {
+        match component {
+            Component::Total => Section::TotalDensity,
+            Component::Diff(component) => Section::DiffDensity { component },
+        }
+    }
+
+    fn _aug_component(component: Component) -> Option<usize> {
+        match component {
+            Component::Total => None,
+            Component::Diff(k) => Some(k),
+        }
+    }
+}
+
+/// Sorts the lines after a grid into the augmentation occupancies and the per-ion magnetic
+/// moments that follow them in spin-polarized files.
+///
+/// Each block of occupancies starts with an `augmentation occupancies <ion> <LMMAX>` line and
+/// has LMMAX values, numbers beyond those, or without any such line as in a CHG, are magnetic
+/// moments. Blocks without a count take every number up to the next grid.
+#[derive(Default)]
+struct AugSplit {
+    in_aug:     bool,
+    remaining:  Option<usize>,  // values left in the current block, `None` if not known
+    in_magmom:  bool,
+}
+
+impl AugSplit {
+    /// Whether `line` belongs to the augmentation occupancies, blank lines in front of the
+    /// magnetic moments do.
+    fn is_aug(&mut self, line: &str) -> bool {
+        if self.in_magmom {
+            return false;
+        }
+        if line.starts_with("aug") {
+            self.in_aug = true;
+            self.remaining = line.split_ascii_whitespace().nth(3).and_then(|t| t.parse().ok());
+            return true;
+        }
+        if line.trim().is_empty() {
+            return true;
+        }
+        match (self.in_aug, self.remaining) {
+            (true, None) => true,
+            (true, Some(n)) if n > 0 => {
+                self.remaining = Some(n.saturating_sub(fortran::tokens(line).count()));
+                true
+            },
+            _ => {
+                self.in_magmom = true;
+                false
+            },
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::options::{FloatStyle, SpeciesSource};
 
-    const SAMPLE: &'static str = "\
+    const SAMPLE: &str = "\
 unknown system
    1.00000000000000
      2.969072   -0.000523   -0.000907
@@ -339,21 +1526,59 @@ augmentation occupancies 2 15
     #[test]
     // #[ignore]
     fn test_read_poscar() {
-        let mut stream = io::Cursor::new(SAMPLE.as_bytes());
-        ChgBase::_read_poscar(&mut stream).unwrap();
+        let mut file = LineReader::new(io::Cursor::new(SAMPLE.as_bytes()));
+        let pos = ChgBase::_read_poscar(&mut file, None).unwrap();
+        assert_eq!(pos.num_sites(), 1);
+
+        // after read_poscar, the next line should be "    2    3    4"
+        assert!(file.advance().unwrap());
+        assert_eq!(file.current(), "    2    3    4\n");
+
+        let header = &SAMPLE[.. 201];
+        let grid = &SAMPLE[201 ..];
+        let variants = [
+            header.replace('\n', "\r\n") + &grid.replace('\n', "\r\n"),      // CRLF
+            header.replacen("\n\n", "\n  \t\n", 1) + grid,                      // blank line with spaces
+            header.replacen("\n\n", "\n", 1) + grid,                              // no blank line
+            header.replacen("\n\n", "\n\n\n\n", 1) + grid,                        // several blank lines
+            header.replacen("   Li\n", "", 1) + grid,                               // VASP 4, no species
+            header.replacen("Direct", "Selective dynamics\nDirect", 1)
+                .replacen("0.000000\n", "0.000000 T T F\n", 1) + grid,
+            header.replacen("unknown system", "", 1) + grid,                      // empty comment
+        ];
+        let expected = ChgBase::from_reader(&mut io::Cursor::new(SAMPLE)).unwrap();
+        for text in variants.iter() {
+            let chg = ChgBase::from_reader(&mut io::Cursor::new(text)).unwrap();
+            assert_eq!(chg.get_poscar().num_sites(), 1, "{}", text);
+            assert_eq!(chg.get_total_chg(), expected.get_total_chg(), "{}", text);
+            assert_eq!(chg.get_diff_chg(), expected.get_diff_chg(), "{}", text);
+        }
+        let crlf = ChgBase::from_bytes(variants[0].as_bytes()).unwrap();
+        assert_eq!(crlf.get_total_aug(), expected.get_total_aug());
+        assert_eq!(crlf.get_diff_aug(), expected.get_diff_aug());
 
-        // after read_poscar, stream's cursor should be at "   32   32   32"
-        let mut it = stream.lines().map(|l| l.unwrap());
-        assert_eq!(it.next(), Some("    2    3    4".to_owned()));
+        // the header ends early, the position lines are counted, not read up to EOF
+        let two_ions = SAMPLE.replacen("     1\n", "     2\n", 1);
+        let err = ChgBase::from_reader(&mut io::Cursor::new(two_ions)).err().unwrap();
+        assert!(matches!(err, ChgError::Poscar(_)), "{}", err);
+        let err = ChgBase::from_reader(&mut io::Cursor::new(&header[.. 169])).err().unwrap();
+        assert_eq!(err.to_string(),
+                   "invalid header, end of file reached, expected 1 ion positions in header at line 8, byte 169");
+        let err = ChgBase::from_reader(&mut io::Cursor::new(header.replacen("     1\n", "     x\n", 1)))
+            .err().unwrap();
+        assert_eq!(err.to_string(), r#"invalid header, invalid ion counts "x" in header at line 7, byte 155"#);
     }
 
     #[test]
     // #[ignore]
     fn test_read_chg() {
         let mut stream = io::Cursor::new(SAMPLE.as_bytes());
-        ChgBase::_read_poscar(&mut stream).unwrap();
+        let mut file = LineReader::new(&mut stream);
+        ChgBase::_read_poscar(&mut file, None).unwrap();
 
-        let chg = ChgBase::_read_chg(&mut stream).unwrap();
+        let (chg, row): (Array3<f64>, _) = ChgBase::_read_chg(&mut file, Section::TotalDensity, &ReadOptions::default(), 1.0)
+            .unwrap();
+        assert_eq!(row, 5);
         assert_eq!(&[2usize, 3, 4], chg.shape());
         assert_eq!(chg[[1, 2, 3]], 0.10568153616E+01);
     }
@@ -362,14 +1587,16 @@ augmentation occupancies 2 15
     // #[ignore]
     fn test_read_aug() {
         let mut stream = io::Cursor::new(SAMPLE.as_bytes());
-        ChgBase::_read_poscar(&mut stream).unwrap();
-        ChgBase::_read_chg(&mut stream).unwrap();
+        let mut file = LineReader::new(&mut stream);
+        ChgBase::_read_poscar(&mut file, None).unwrap();
+        ChgBase::_read_chg::<f64>(&mut file, Section::TotalDensity, &ReadOptions::default(), 1.0).unwrap();
 
-        let aug = ChgBase::_read_raw_aug(&mut stream).unwrap();
+        let (aug, magmom) = ChgBase::_read_raw_aug(&mut file, None, &ReadOptions::default()).unwrap();
         assert!(aug.trim_end().ends_with("-0.2068344E-05"));
+        assert_eq!(magmom, "");
 
-        if let Some(line) = stream.lines().map(|l| l.unwrap()).next() {
-            assert!(line.split_ascii_whitespace().all(|s| s.parse::<usize>().is_ok()));
+        if file.advance().unwrap() {
+            assert!(file.current().split_ascii_whitespace().all(|s| s.parse::<usize>().is_ok()));
         }
     }
 
@@ -389,7 +1616,7 @@ augmentation occupancies 2 15
         let chgcar = ChgBase::from_reader(&mut istream).unwrap();
 
         let mut ostream = io::Cursor::new(vec![0u8; 0]);
-        ChgBase::_write_chg(&mut ostream, chgcar.get_total_chg(), 5).unwrap();
+        ChgBase::_write_chg(&mut ostream, chgcar.get_total_chg(), 1.0, &NumberFormat::default_for(ChgType::Chgcar)).unwrap();
         println!("{}", String::from_utf8(ostream.get_ref().clone()).unwrap());
     }
 
@@ -404,4 +1631,751 @@ augmentation occupancies 2 15
         println!("{}", String::from_utf8(ostream.get_ref().clone()).unwrap());
         Ok(())
     }
+
+    #[test]
+    fn test_write_chg_layout() -> Result<()> {
+        let chgcar = ChgBase::from_bytes(SAMPLE.as_bytes())?;
+        assert_eq!(chgcar.get_chgtype(), ChgType::Chgcar);
+
+        let mut out = vec![];
+        chgcar.write_writer(&mut out, ChgType::Chg)?;
+        let text = String::from_utf8(out).unwrap();
+        let lines = text.lines().collect::<Vec<_>>();
+        assert_eq!(lines[10], "    2    3    4");
+        assert_eq!(lines[11], " 0.44062E+00 0.44635E+00 0.46295E+00 0.48881E+00 0.52212E+00 \
+                                0.56203E+00 0.60956E+00 0.66672E+00 0.73418E+00 0.80885E+00");
+        assert_eq!(lines[13], " 0.10677E+01 0.10709E+01 0.10677E+01 0.10568E+01");
+        assert_eq!(lines[14], "    2    3    4");
+        assert_eq!(lines.len(), 18);
+        assert!(!text.contains("augmentation"));
+
+        let chg = ChgBase::from_bytes(text.as_bytes())?;
+        assert_eq!(chg.get_chgtype(), ChgType::Chg);
+        assert_eq!(chg.get_diff_chg().len(), 1);
+        assert!(chg.get_total_chg().iter().zip(chgcar.get_total_chg())
+                .all(|(a, b)| (a - b).abs() <= b.abs() * 1E-4));
+        let meta = ChgBase::_scan(&mut LineReader::new(text.as_bytes()), None)?;
+        assert_eq!(meta.get_chgtype(), ChgType::Chg);
+
+        let mut out = vec![];
+        chg.write_writer(&mut out, ChgType::Parchg)?;
+        assert_eq!(ChgBase::from_bytes(&out)?.get_chgtype(), ChgType::Parchg);
+
+        // blank lines after a grid are no augmentation occupancies
+        for (text, chgtype) in &[(String::from_utf8(out).unwrap(), ChgType::Parchg), (text, ChgType::Chg)] {
+            let ngrid = "    2    3    4\n";
+            let second = text.rfind(ngrid).unwrap();
+            let blank = [&text[.. second], " \n\n", &text[second ..], "\n  \n"].concat();
+            let read = ChgBase::from_bytes(blank.as_bytes())?;
+            assert_eq!(read.get_chgtype(), *chgtype);
+            assert_eq!(read.get_total_aug(), None);
+            assert_eq!(read.get_diff_aug(), &vec![String::new()]);
+            let meta = ChgBase::_scan(&mut LineReader::new(blank.as_bytes()), None)?;
+            assert_eq!(meta.get_chgtype(), *chgtype);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_magmom() -> Result<()> {
+        // a spin-polarized file has the moments of the ions in front of the magnetization
+        let magmom = "  0.600000000000E+00\n";
+        let second = SAMPLE.rfind("    2    3    4").unwrap();
+        let text = [&SAMPLE[.. second], magmom, &SAMPLE[second ..]].concat();
+        let chgcar = ChgBase::from_bytes(text.as_bytes())?;
+        assert_eq!(chgcar.get_chgtype(), ChgType::Chgcar);
+        assert_eq!(chgcar.get_total_aug(), ChgBase::from_bytes(SAMPLE.as_bytes())?.get_total_aug());
+        assert_eq!(chgcar.get_magmom(), &vec![magmom.to_owned(), String::new()]);
+        let meta = ChgBase::_scan(&mut LineReader::new(text.as_bytes()), None)?;
+        assert_eq!(meta.get_offsets()[0].magmom.map(|s| s.offset), Some(second as u64));
+
+        for chgtype in [ChgType::Chgcar, ChgType::Chg, ChgType::Parchg] {
+            let mut out = vec![];
+            chgcar.write_writer(&mut out, chgtype)?;
+            let written = String::from_utf8(out).unwrap();
+            assert!(written.contains(&format!("\n{}    2    3    4\n", magmom)));
+            let chg = ChgBase::from_bytes(written.as_bytes())?;
+            assert_eq!(chg.get_chgtype(), chgtype);
+            assert_eq!(chg.get_magmom(), chgcar.get_magmom());
+            let meta = ChgBase::_scan(&mut LineReader::new(written.as_bytes()), None)?;
+            assert_eq!(meta.get_chgtype(), chgtype);
+            if chgtype != ChgType::Chgcar {
+                let mut out = vec![];
+                chg.write_writer(&mut out, chg.get_chgtype())?;
+                assert_eq!(String::from_utf8(out).unwrap(), written);
+            }
+        }
+
+        // occupancies without a count take every number up to the next grid
+        let uncounted = text.replace(" 15\n", "\n");
+        let chgcar = ChgBase::from_bytes(uncounted.as_bytes())?;
+        assert!(chgcar.get_total_aug().unwrap().ends_with(magmom));
+        assert_eq!(chgcar.get_magmom()[0], "");
+        Ok(())
+    }
+
+    #[test]
+    fn test_number_format() -> Result<()> {
+        let chgcar = ChgBase::from_bytes(SAMPLE.as_bytes())?;
+        let write = |opts: &WriteOptions| -> Result<String> {
+            let mut out = vec![];
+            chgcar.write_writer_with(&mut out, ChgType::Chgcar, opts)?;
+            Ok(String::from_utf8(out).unwrap())
+        };
+
+        // VASP's own formatting comes back unchanged
+        let text = write(&WriteOptions::default())?;
+        let grid = |text: &str| text.lines().skip(10).take(6).map(str::to_owned).collect::<Vec<_>>();
+        assert_eq!(grid(&text), grid(SAMPLE));
+
+        let format = NumberFormat { digits: 4, per_row: 8, width: 12, style: FloatStyle::Fortran };
+        let text = write(&WriteOptions { format: Some(format), ..Default::default() })?;
+        assert_eq!(text.lines().nth(11).unwrap(), concat!("   0.4406E+00   0.4464E+00   0.4629E+00   0.4888E+00",
+                                                          "   0.5221E+00   0.5620E+00   0.6096E+00   0.6667E+00"));
+        assert_eq!(text.lines().nth(14).unwrap(), "augmentation occupancies 1 15");
+
+        let format = NumberFormat { style: FloatStyle::Rust, ..format };
+        let text = write(&WriteOptions { format: Some(format), ..Default::default() })?;
+        assert!(text.lines().nth(11).unwrap().starts_with("     4.406E-1     4.464E-1"));
+        assert_eq!(ChgBase::from_bytes(text.as_bytes())?.get_chgtype(), ChgType::Chgcar);
+
+        let format = NumberFormat { per_row: 0, ..format };
+        assert!(write(&WriteOptions { format: Some(format), ..Default::default() }).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_lossless() -> Result<()> {
+        let opts = ReadOptions { lossless: true, ..Default::default() };
+        let write = |chg: &ChgBase, chgtype: ChgType| -> Result<String> {
+            let mut out = vec![];
+            chg.write_writer(&mut out, chgtype)?;
+            Ok(String::from_utf8(out).unwrap())
+        };
+        let text = SAMPLE.replacen("   1.00000000000000", "   1.0", 1)
+            .replacen(" 0.44635237036E+00", " 0.446352370361E+00", 1);
+
+        let mut chg = ChgBase::from_bytes_with(text.as_bytes(), &opts)?;
+        assert_eq!(write(&chg, ChgType::Chgcar)?, text);
+        assert_ne!(write(&ChgBase::from_bytes(text.as_bytes())?, ChgType::Chgcar)?, text);
+        assert!(!write(&chg, ChgType::Parchg)?.contains("augmentation"));
+
+        // only the modified grid is formatted again
+        chg.get_mut_diff_chg()[0][[0, 0, 0]] = 0.5;
+        let written = write(&chg, ChgType::Chgcar)?;
+        let (head, tail) = written.split_at(text.rfind("    2    3    4").unwrap());
+        assert_eq!(head, &text[.. head.len()]);
+        assert!(tail.starts_with("    2    3    4\n 0.50000000000E+00 0.44635237036E+00"));
+        assert!(tail.ends_with(&text[text.rfind("augmentation occupancies 1").unwrap() ..]));
+
+        // an equal header is still copied
+        *chg.get_mut_poscar() = ChgBase::from_bytes(SAMPLE.as_bytes())?.get_poscar().clone();
+        assert!(write(&chg, ChgType::Chgcar)?.contains("\n   1.0\n"));
+        *chg.get_mut_axis_scales() = Some([1.0, 1.0, 1.0]);
+        assert!(!write(&chg, ChgType::Chgcar)?.contains("\n   1.0\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_zero_aug() -> Result<()> {
+        let chgcar = ChgBase::from_bytes(SAMPLE.as_bytes())?;
+        let chg = ChgBase::from_builder(chgcar.get_total_chg().clone(), vec![], chgcar.get_poscar().clone());
+        let mut out = vec![];
+        assert!(matches!(chg.write_writer(&mut out, ChgType::Chgcar),
+                         Err(ChgError::MissingAugmentation(Component::Total))));
+        assert!(out.is_empty());
+
+        let opts = WriteOptions { zero_aug_lmmax: Some(vec![7]), ..Default::default() };
+        chg.write_writer_with(&mut out, ChgType::Chgcar, &opts)?;
+        let aug = "augmentation occupancies   1   7\n\
+                   \x20 0.0000000E+00  0.0000000E+00  0.0000000E+00  0.0000000E+00  0.0000000E+00\n\
+                   \x20 0.0000000E+00  0.0000000E+00\n";
+        let written = ChgBase::from_bytes(&out)?;
+        assert_eq!(written.get_total_aug().map(String::as_str), Some(aug));
+        assert_eq!(written.get_chgtype(), ChgType::Chgcar);
+
+        let opts = WriteOptions { zero_aug_lmmax: Some(vec![7, 7]), ..Default::default() };
+        assert!(chg.write_writer_with(&mut vec![], ChgType::Chgcar, &opts).is_err());
+
+        // a file read without occupancies is no CHGCAR either, unless LMMAX is given
+        let mut parchg = vec![];
+        chg.write_writer(&mut parchg, ChgType::Parchg)?;
+        let mut parchg = ChgBase::from_bytes(&parchg)?;
+        let mut out = vec![];
+        assert!(matches!(parchg.write_writer(&mut out, ChgType::Chgcar),
+                         Err(ChgError::MissingAugmentation(Component::Total))));
+        assert!(out.is_empty());
+        parchg.aug = Some(" \n".to_owned());
+        assert!(matches!(parchg.write_writer(&mut out, ChgType::Chgcar),
+                         Err(ChgError::MissingAugmentation(Component::Total))));
+        let opts = WriteOptions { zero_aug_lmmax: Some(vec![7]), ..Default::default() };
+        let mut out = vec![];
+        parchg.write_writer_with(&mut out, ChgType::Chgcar, &opts)?;
+        assert_eq!(ChgBase::from_bytes(&out)?.get_total_aug().map(String::as_str), Some(aug));
+
+        // a diff component without occupancies is an error too, existing ones are kept
+        let mut chg = chgcar;
+        chg.get_mut_diff_chg().push(Array3::zeros((2, 3, 4)));
+        assert!(matches!(chg.write_writer(&mut vec![], ChgType::Chgcar),
+                         Err(ChgError::MissingAugmentation(Component::Diff(1)))));
+        let opts = WriteOptions { zero_aug_lmmax: Some(vec![15]), ..Default::default() };
+        let mut out = vec![];
+        chg.write_writer_with(&mut out, ChgType::Chgcar, &opts)?;
+        let written = ChgBase::from_bytes(&out)?;
+        assert_eq!(written.get_total_aug(), chg.get_total_aug());
+        assert_eq!(written.get_diff_aug()[1].trim_end().lines().count(), 4);
+        assert!(written.get_diff_aug()[1].starts_with("augmentation occupancies   1  15\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_spin_channels() -> Result<()> {
+        let chgcar = ChgBase::from_bytes(SAMPLE.as_bytes())?;
+        let (up, down) = (chgcar.spin_up()?, chgcar.spin_down()?);
+        assert!(up.get_diff_chg().is_empty() && up.get_total_aug().is_none());
+        assert_eq!(down.get_provenance().get_operations(), &["spin down".to_owned()]);
+        let volume = chgcar.get_poscar().scaled_volume();
+        let channels = up.get_total_chg().iter().zip(down.get_total_chg());
+        let expected = chgcar.get_total_chg().iter().zip(&chgcar.get_diff_chg()[0]);
+        for ((&up, &down), (&rho, &mag)) in channels.zip(expected) {
+            assert!((up + down - rho).abs() < 1E-12 && ((up - down) * volume - mag).abs() < 1E-12);
+        }
+
+        let rho = chgcar.get_total_chg().clone();
+        let pos = chgcar.get_poscar().clone();
+        let chg = ChgBase::from_builder(rho.clone(), vec![], pos.clone());
+        assert!(matches!(chg.spin_up(), Err(ChgError::MissingComponent(Component::Diff(0)))));
+        let chg = ChgBase::from_builder(rho.clone(), vec![rho.clone(); 3], pos.clone());
+        assert!(chg.spin_down().is_err());
+        let chg = ChgBase::from_builder(rho, vec![Array3::zeros((1, 1, 1))], pos);
+        assert!(chg.spin_up().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_provenance_title() -> Result<()> {
+        let mut chg = ChgBase::from_bytes(SAMPLE.as_bytes())?;
+        assert!(chg.get_provenance().is_empty());
+        let opts = WriteOptions { provenance_title: true, ..Default::default() };
+        let mut out = vec![];
+        chg.write_writer_with(&mut out, ChgType::Chgcar, &opts)?;
+        assert!(out.starts_with(b"unknown system\n"));
+
+        chg.get_mut_provenance().record("sum with CHGCAR_b");
+        let mut out = vec![];
+        chg.write_writer_with(&mut out, ChgType::Chgcar, &opts)?;
+        assert!(out.starts_with(b"sum with CHGCAR_b\n"));
+        let written = ChgBase::from_bytes(&out)?;
+        assert_eq!(written.get_total_chg(), chg.get_total_chg());
+        assert_eq!(written.get_poscar().to_string().lines().nth(1), chg.get_poscar().to_string().lines().nth(1));
+        Ok(())
+    }
+
+    #[cfg(not(feature = "json"))]
+    #[test]
+    fn test_provenance_without_json() {
+        let chg = ChgBase::from_bytes(SAMPLE.as_bytes()).unwrap();
+        let path = std::env::temp_dir().join(format!("vaspchg_no_json_{}", std::process::id()));
+        let opts = WriteOptions { provenance_sidecar: true, ..Default::default() };
+        match chg.write_file_with(&path, ChgType::Chgcar, &opts) {
+            Err(ChgError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::Unsupported),
+            _ => panic!("sidecar written without the json feature"),
+        }
+        assert!(!path.exists());
+        let opts = ReadOptions { provenance: true, ..Default::default() };
+        match ChgBase::from_file_with(&path, &opts) {
+            Err(ChgError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::Unsupported),
+            _ => panic!("provenance recorded without the json feature"),
+        }
+    }
+
+    #[test]
+    fn test_scale_factors() -> Result<()> {
+        let write = |chgcar: &ChgBase| -> Result<String> {
+            let mut out = vec![];
+            chgcar.write_writer(&mut out, ChgType::Chgcar)?;
+            Ok(String::from_utf8(out).unwrap())
+        };
+        let volume = ChgBase::from_bytes(SAMPLE.as_bytes())?.get_poscar().scaled_volume();
+        let first = 0.44062142953E+00;
+
+        // negative scale is the volume of the cell
+        let text = SAMPLE.replacen("   1.00000000000000", "  -20.0", 1);
+        let chgcar = ChgBase::from_bytes(text.as_bytes())?;
+        assert!((chgcar.get_poscar().scaled_volume() - 20.0).abs() < 1E-10);
+        assert!((chgcar.get_total_chg()[[0, 0, 0]] - first / 20.0).abs() < 1E-12);
+        assert_eq!(chgcar.get_axis_scales(), None);
+        let written = write(&chgcar)?;
+        assert_eq!(written.lines().nth(1).unwrap().trim(), "-20.000000");
+        let reread = ChgBase::from_bytes(written.as_bytes())?;
+        assert!((reread.get_poscar().scaled_volume() - 20.0).abs() < 1E-10);
+        assert!((reread.get_total_chg()[[0, 0, 0]] - first / 20.0).abs() < 1E-12);
+
+        // three scales multiply the Cartesian components
+        let text = SAMPLE.replacen("   1.00000000000000", "   2.0 1.0 1.0", 1)
+            .replacen("Direct\n  0.000000", "Cartesian\n  0.500000", 1);
+        let chgcar = ChgBase::from_bytes(text.as_bytes())?;
+        assert_eq!(chgcar.get_axis_scales(), Some([2.0, 1.0, 1.0]));
+        assert!((chgcar.get_poscar().scaled_volume() - 2.0 * volume).abs() < 1E-10);
+        assert_eq!(chgcar.get_poscar().scaled_lattice_vectors()[1], [-1.97461, 2.800110, 0.000907]);
+        assert_eq!(chgcar.get_poscar().scaled_cart_positions()[0], [1.0, 0.0, 0.0]);
+        assert!((chgcar.get_total_chg()[[0, 0, 0]] - first / (2.0 * volume)).abs() < 1E-12);
+        let written = write(&chgcar)?;
+        let lines = written.lines().collect::<Vec<_>>();
+        assert_eq!(lines[1], "   2.000000  1.000000  1.000000");
+        assert_eq!(lines[3].split_ascii_whitespace().collect::<Vec<_>>(), ["-0.987305", "2.800110", "0.000907"]);
+        assert_eq!(lines[8].split_ascii_whitespace().collect::<Vec<_>>(), ["0.500000", "0.000000", "0.000000"]);
+        let reread = ChgBase::from_bytes(written.as_bytes())?;
+        assert_eq!(reread.get_axis_scales(), Some([2.0, 1.0, 1.0]));
+        assert_eq!(reread.get_poscar().scaled_lattice_vectors(), chgcar.get_poscar().scaled_lattice_vectors());
+        assert_eq!(reread.get_total_chg(), chgcar.get_total_chg());
+        assert_eq!(write(&reread)?.lines().take(10).collect::<Vec<_>>(), lines[.. 10]);
+
+        // dropping the scales writes the scaled lattice with a single scale of 1
+        let mut chgcar = chgcar;
+        *chgcar.get_mut_axis_scales() = None;
+        let reread = ChgBase::from_bytes(write(&chgcar)?.as_bytes())?;
+        assert_eq!(reread.get_axis_scales(), None);
+        assert_eq!(reread.get_poscar().scaled_lattice_vectors(), chgcar.get_poscar().scaled_lattice_vectors());
+
+        for scales in &["1.0 -2.0 1.0", "1.0 x 1.0", "1.0 0.0 1.0"] {
+            let text = SAMPLE.replacen("1.00000000000000", scales, 1);
+            match ChgBase::from_bytes(text.as_bytes()) {
+                Err(ChgError::Header { at, .. }) => assert_eq!((at.line, at.offset), (2, 15)),
+                _ => panic!("invalid scales {:?} not reported", scales),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_errors() {
+        let (head, _) = SAMPLE.split_at(SAMPLE.find("    2    3    4").unwrap());
+        let mut stream = io::Cursor::new(head);
+        assert!(matches!(ChgBase::from_reader(&mut stream), Err(ChgError::BadGridLine { .. })));
+
+        let mut stream = io::Cursor::new(&head[.. 60]);
+        match ChgBase::from_reader(&mut stream) {
+            Err(ChgError::Header { at, .. }) => assert_eq!(at.section, Section::Header),
+            _ => panic!("truncated header not reported"),
+        }
+
+        for (empty, offset) in &[("", 0), ("\n", 1)] {
+            match ChgBase::from_reader(&mut io::Cursor::new(empty)) {
+                Err(ChgError::Header { at, .. }) =>
+                    assert_eq!(at, Location { section: Section::Header, line: 1, offset: *offset }),
+                _ => panic!("empty input not reported"),
+            }
+        }
+
+        let (head, _) = SAMPLE.split_at(SAMPLE.find(" 0.10677009023E+01").unwrap());
+        let mut stream = io::Cursor::new(head);
+        assert!(matches!(ChgBase::from_reader(&mut stream),
+                         Err(ChgError::ShortGrid { expected: 24, found: 15, .. })));
+
+        let broken = SAMPLE.replacen("    2    3    4", "    2    3", 1);
+        let mut stream = io::Cursor::new(broken);
+        match ChgBase::from_reader(&mut stream) {
+            Err(ChgError::BadGridLine { line, at }) => {
+                assert_eq!(line, "    2    3");
+                assert_eq!(at, Location { section: Section::TotalDensity, line: 11, offset: 201 });
+            },
+            _ => panic!("broken grid line not reported"),
+        }
+
+        // a product that overflows, or a grid that cannot be held in memory
+        for ngrid in &[" 4000000000 4000000000 4000000000", " 1 1 2305843009213693952"] {
+            let broken = SAMPLE.replacen("    2    3    4", ngrid, 1);
+            assert!(matches!(ChgBase::from_reader(&mut io::Cursor::new(broken)),
+                             Err(ChgError::BadGridLine { .. })));
+        }
+        let broken = SAMPLE.replacen("    2    3    4", " 1 1 288230376151711744", 1);
+        match ChgBase::from_reader(&mut io::Cursor::new(broken)) {
+            Err(ChgError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::OutOfMemory),
+            _ => panic!("allocation failure not reported"),
+        }
+    }
+
+    #[test]
+    fn test_error_location() {
+        let broken = SAMPLE.replacen("0.48881056285E+00", "0.48881O56285E+00", 1);
+        let mut stream = io::Cursor::new(broken.as_str());
+        match ChgBase::from_reader(&mut stream) {
+            Err(ChgError::InvalidNumber { token, at }) => {
+                assert_eq!(token, "0.48881O56285E+00");
+                assert_eq!(at.section, Section::TotalDensity);
+                assert_eq!(at.line, 12);
+                assert_eq!(&broken[at.offset as usize ..][.. token.len()], token);
+            },
+            _ => panic!("corrupted token not reported"),
+        }
+
+        let broken = SAMPLE.replacen("-0.2038144E-05", "-0.2038144F-05", 1);
+        let mut stream = io::Cursor::new(broken.as_str());
+        match ChgBase::from_reader(&mut stream) {
+            Err(ChgError::InvalidNumber { token, at }) => {
+                assert_eq!(token, "-0.2038144F-05");
+                assert_eq!(at.section, Section::DiffAugmentation { component: 0, block: 1 });
+                assert_eq!(at.line, 34);
+                assert_eq!(&broken[at.offset as usize ..][.. token.len()], token);
+            },
+            _ => panic!("corrupted augmentation not reported"),
+        }
+
+        let broken = SAMPLE.replacen("0.12668153616E+01", "0.12668153616E+0l", 1);
+        let mut stream = io::Cursor::new(broken.as_str());
+        let err = ChgBase::from_reader(&mut stream).err().unwrap();
+        assert_eq!(err.to_string(),
+                   "invalid number \"0.12668153616E+0l\" in diff component 0 at line 30, byte 1605");
+    }
+
+    #[test]
+    fn test_from_non_seekable() {
+        // Only implements `Read`, just like stdin or a decompressor
+        struct Pipe<'a>(&'a [u8]);
+        impl io::Read for Pipe<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let n = buf.len().min(self.0.len()).min(7);
+                buf[.. n].copy_from_slice(&self.0[.. n]);
+                self.0 = &self.0[n ..];
+                Ok(n)
+            }
+        }
+
+        let mut stream = BufReader::new(Pipe(SAMPLE.as_bytes()));
+        let chgcar = ChgBase::from_reader(&mut stream).unwrap();
+        assert_eq!(chgcar.get_ngrid(), &[2, 3, 4]);
+        assert_eq!(chgcar.get_diff_chg().len(), 1);
+        assert_eq!(chgcar.get_diff_aug().len(), 1);
+        assert_eq!(chgcar.get_diff_chg()[0][[1, 2, 3]], 0.12668153616E+01);
+        assert!(chgcar.get_total_aug().unwrap().ends_with("-0.2068344E-05\n"));
+    }
+
+    #[test]
+    fn test_fortran_artefacts() {
+        let glued = SAMPLE
+            .replacen(" 0.46294638829E+00 0.48881056285E+00", " 0.46294638829E+00-0.48881056285E+00", 1)
+            .replacen(" 0.56203432815E+00", "******************", 1)
+            .replacen(" 0.3964234E-01\n  0.5875445E-05", " ***************\n  0.5875445E-05", 1);
+        let mut stream = io::Cursor::new(glued.as_str());
+        match ChgBase::from_reader(&mut stream) {
+            Err(ChgError::Overflow { token, at }) => {
+                assert_eq!(token, "******************");
+                assert_eq!(at, Location { section: Section::TotalDensity, line: 13, offset: 308 });
+            },
+            _ => panic!("overflow not reported"),
+        }
+
+        let opts = ReadOptions { overflow: OverflowPolicy::NaN, ..Default::default() };
+        let chgcar = ChgBase::from_reader_with(&mut io::Cursor::new(glued.as_str()), &opts).unwrap();
+        let chg = chgcar.get_total_chg() * chgcar.get_poscar().scaled_volume();
+        assert!((chg[[1, 1, 0]] + 0.48881056285).abs() < 1E-12);
+        assert!(chg[[1, 2, 0]].is_nan());
+        assert!((chg[[0, 0, 1]] - 0.60956087775).abs() < 1E-12);
+        assert!(chgcar.get_total_aug().unwrap().contains("***************"));
+
+        let opts = ReadOptions { overflow: OverflowPolicy::Clamp(1E+100), ..Default::default() };
+        let chgcar = ChgBase::from_reader_with(&mut io::Cursor::new(glued.as_str()), &opts).unwrap();
+        let chg = chgcar.get_total_chg() * chgcar.get_poscar().scaled_volume();
+        assert!((chg[[1, 2, 0]] / 1E+100 - 1.0).abs() < 1E-12);
+        assert_eq!(chgcar.get_diff_chg().len(), 1);
+
+        // two overflowed fields glued together, the field width is learned from the first line
+        let glued = SAMPLE.replacen(" 0.56203432815E+00 0.60956087775E+00",
+                                    "************************************", 1);
+        let opts = ReadOptions { overflow: OverflowPolicy::NaN, ..Default::default() };
+        let chgcar = ChgBase::from_reader_with(&mut io::Cursor::new(glued.as_str()), &opts).unwrap();
+        assert!(chgcar.get_total_chg()[[1, 2, 0]].is_nan());
+        assert!(chgcar.get_total_chg()[[0, 0, 1]].is_nan());
+        assert!(!chgcar.get_total_chg()[[1, 0, 1]].is_nan());
+
+        // glued fields on the first line, the field width is taken from the number after them
+        let glued = SAMPLE.replacen(" 0.44062142953E+00 0.44635237036E+00",
+                                    "************************************", 1);
+        let chgcar = ChgBase::from_reader_with(&mut io::Cursor::new(glued.as_str()), &opts).unwrap();
+        let chg = chgcar.get_total_chg() * chgcar.get_poscar().scaled_volume();
+        assert!(chg[[0, 0, 0]].is_nan());
+        assert!(chg[[1, 0, 0]].is_nan());
+        assert!((chg[[0, 1, 0]] - 0.46294638829).abs() < 1E-12);
+        assert!((chg[[1, 2, 0]] - 0.56203432815).abs() < 1E-12);
+        assert_eq!(ChgBase::_field_width(" 0.44062142953E+00 0.44635237036E+00"), Some(18));
+        assert_eq!(ChgBase::_field_width("************ 0.44062E+00"), Some(12));
+        assert_eq!(ChgBase::_field_width(" ***********"), None);
+
+        let aug_only = SAMPLE.replacen(" 0.3964234E-01\n  0.5875445E-05", " ***************\n  0.5875445E-05", 1);
+        match ChgBase::from_reader(&mut io::Cursor::new(aug_only.as_str())) {
+            Err(ChgError::Overflow { at, .. }) =>
+                assert_eq!(at.section, Section::TotalAugmentation { block: 1 }),
+            _ => panic!("overflow in augmentation not reported"),
+        }
+    }
+
+    #[test]
+    fn test_fortran_exponents() {
+        let exotic = SAMPLE
+            .replacen("0.44062142953E+00", "0.44062142953D+00", 1)
+            .replacen(" 0.44635237036E+00", " 0.44635237036-105", 1)
+            .replacen(" 0.46294638829E+00 0.48881056285E+00", " 0.46294638829d+00-0.48881056285+100", 1)
+            .replacen("0.1033253E-02", "0.1033253D-02", 1);
+        let chgcar = ChgBase::from_reader(&mut io::Cursor::new(exotic.as_str())).unwrap();
+        let chg = chgcar.get_total_chg() * chgcar.get_poscar().scaled_volume();
+        assert!((chg[[0, 0, 0]] - 0.44062142953).abs() < 1E-12);
+        assert!((chg[[1, 0, 0]] / 0.44635237036E-105 - 1.0).abs() < 1E-12);
+        assert!((chg[[0, 1, 0]] - 0.46294638829).abs() < 1E-12);
+        assert!((chg[[1, 1, 0]] / -0.48881056285E+100 - 1.0).abs() < 1E-12);
+        assert!(chgcar.get_total_aug().unwrap().contains("0.1033253D-02"));
+    }
+
+    #[test]
+    fn test_scan() {
+        let mut file = LineReader::new(io::Cursor::new(SAMPLE));
+        let meta = ChgBase::_scan(&mut file, None).unwrap();
+        assert_eq!(meta.get_ngrid(), &[2, 3, 4]);
+        assert_eq!(meta.get_ncomponents(), 2);
+        assert!(meta.has_aug());
+        assert_eq!(meta.get_chgtype(), ChgType::Chgcar);
+        assert!(meta.is_spin_polarized());
+        assert_eq!(meta.get_poscar().num_sites(), 1);
+
+        let offsets = meta.get_offsets();
+        let aug = SAMPLE.find("augmentation occupancies 1").unwrap() as u64;
+        let grid = SAMPLE.rfind("    2    3    4").unwrap() as u64;
+        let aug2 = SAMPLE.rfind("augmentation occupancies 1").unwrap() as u64;
+        let start = |offset: u64| SectionStart {
+            offset, line: SAMPLE[.. offset as usize].lines().count() + 1,
+        };
+        assert_eq!(start(201).line, 11);
+        assert_eq!(offsets, &[ComponentOffsets { grid: start(201), aug: Some(start(aug)), magmom: None },
+                              ComponentOffsets { grid: start(grid), aug: Some(start(aug2)), magmom: None }]);
+        assert_eq!(meta.get_component_offsets(Component::Diff(0)), Some(&offsets[1]));
+        assert_eq!(meta.get_component_offsets(Component::Diff(1)), None);
+
+        // numbers are not converted, but a missing value is still noticed
+        let broken = SAMPLE.replacen("0.48881056285E+00", "0.48881O56285E+00", 1);
+        let mut file = LineReader::new(io::Cursor::new(broken));
+        assert!(ChgBase::_scan(&mut file, None).is_ok());
+        let (head, _) = SAMPLE.split_at(SAMPLE.find(" 0.10677009023E+01").unwrap());
+        let mut file = LineReader::new(io::Cursor::new(head));
+        assert!(matches!(ChgBase::_scan(&mut file, None), Err(ChgError::ShortGrid { .. })));
+    }
+
+    #[test]
+    fn test_from_bytes() {
+        let expected = ChgBase::from_reader(&mut io::Cursor::new(SAMPLE)).unwrap();
+        let chg = ChgBase::from_bytes(SAMPLE.as_bytes()).unwrap();
+        assert_eq!(chg.get_total_chg(), expected.get_total_chg());
+        assert_eq!(chg.get_total_aug(), expected.get_total_aug());
+        assert_eq!(chg.get_diff_chg(), expected.get_diff_chg());
+        assert_eq!(chg.get_diff_aug(), expected.get_diff_aug());
+
+        let broken = SAMPLE.replacen("0.12668153616E+01", "0.12668153616E+0l", 1);
+        assert_eq!(ChgBase::from_bytes(broken.as_bytes()).err().unwrap().to_string(),
+                   ChgBase::from_reader(&mut broken.as_bytes()).err().unwrap().to_string());
+        assert!(ChgBase::from_bytes(b"").is_err());
+    }
+
+    #[test]
+    fn test_read_component() {
+        let full = ChgBase::from_reader(&mut io::Cursor::new(SAMPLE)).unwrap();
+        let read = |component| {
+            let mut file = LineReader::new(io::Cursor::new(SAMPLE));
+            let pos = ChgBase::_read_poscar(&mut file, None)?;
+            ChgBase::_skip_to_component(&mut file, component)?;
+            ChgBase::_read_component_chg::<f64>(&mut file, &pos, component, &ReadOptions::default())
+        };
+        assert_eq!(&read(Component::Total).unwrap(), full.get_total_chg());
+        assert_eq!(&read(Component::Diff(0)).unwrap(), &full.get_diff_chg()[0]);
+        assert!(matches!(read(Component::Diff(1)),
+                         Err(ChgError::MissingComponent(Component::Diff(1)))));
+
+        // continue from the offsets of the scan, errors still point to the right line
+        let meta = ChgBase::_scan(&mut LineReader::new(io::Cursor::new(SAMPLE)), None).unwrap();
+        let at = |start: SectionStart| {
+            let mut file = io::Cursor::new(SAMPLE);
+            file.set_position(start.offset);
+            LineReader::starting_at(file, start.offset, start.line)
+        };
+        let offsets = meta.get_component_offsets(Component::Diff(0)).unwrap();
+        let mut file = at(offsets.grid);
+        let opts = ReadOptions::default();
+        let diff = ChgBase::_read_component_chg::<f64>(&mut file, meta.get_poscar(), Component::Diff(0), &opts)
+            .unwrap();
+        assert_eq!(&diff, &full.get_diff_chg()[0]);
+        let mut file = at(offsets.aug.unwrap());
+        let (aug, _) = ChgBase::_read_raw_aug(&mut file, Some(0), &ReadOptions::default()).unwrap();
+        assert_eq!(&aug, &full.get_diff_aug()[0]);
+
+        let broken = SAMPLE.replacen("0.12668153616E+01", "0.12668153616E+0l", 1);
+        let mut file = io::Cursor::new(broken.as_str());
+        file.set_position(offsets.grid.offset);
+        let mut file = LineReader::starting_at(file, offsets.grid.offset, offsets.grid.line);
+        let err = ChgBase::_read_component_chg::<f64>(&mut file, meta.get_poscar(), Component::Diff(0), &opts)
+            .unwrap_err();
+        assert!(matches!(err, ChgError::InvalidNumber {
+            at: Location { line: 30, offset: 1605, .. }, ..
+        }));
+    }
+
+    #[test]
+    #[cfg(feature = "rayon")]
+    fn test_read_values_par() {
+        let serial = |text: &str, opts: &ReadOptions| {
+            let mut file = LineReader::new(io::Cursor::new(text));
+            let ngrid = ChgBase::_read_ngrid(&mut file, Section::TotalDensity)?;
+            let len = ngrid.iter().product();
+            let mut buf = vec![];
+            ChgBase::_read_values(&mut file, Section::TotalDensity, len, opts, Some(|v| buf.push(v)))?;
+            buf.truncate(len);
+            Ok(buf)
+        };
+        let parallel = |text: &str, opts: &ReadOptions, batch, chunk| {
+            let mut file = LineReader::new(io::Cursor::new(text));
+            let ngrid = ChgBase::_read_ngrid(&mut file, Section::TotalDensity)?;
+            let mut buf = vec![];
+            ChgBase::_read_values_par(&mut file, Section::TotalDensity, ngrid.iter().product(), opts,
+                                      &mut buf, 1.0, (batch, chunk))?;
+            Ok(buf)
+        };
+        let bits = |r: Result<Vec<f64>>| r.unwrap().iter().map(|x| x.to_bits()).collect::<Vec<_>>();
+        let grid = &SAMPLE[201 ..];
+        let overflowed = grid.replacen("0.10000382501E+01", "*****************", 1)
+            .replacen("0.56203432815E+00 0.60956087775E+00", "***********************************", 1);
+        let glued = grid.replace(" 0.", "+0.");
+        let broken = grid.replacen("0.10353398391E+01", "0.1035339839lE+01", 1)
+            .replacen("0.10677009023E+01", "0.1067700902xE+01", 1);
+        let nan = ReadOptions { overflow: OverflowPolicy::NaN, ..Default::default() };
+        let clamp = ReadOptions { overflow: OverflowPolicy::Clamp(1E30), ..Default::default() };
+
+        for &(text, opts) in &[(grid, &ReadOptions::default()), (&overflowed, &nan), (&overflowed, &clamp),
+                               (&glued, &ReadOptions::default())] {
+            let expected = bits(serial(text, opts));
+            assert_eq!(expected.len(), 24);
+            for &(batch, chunk) in &[(1, 1), (2, 1), (3, 2), (4, 4), (100, 3), (100, 100)] {
+                assert_eq!(bits(parallel(text, opts, batch, chunk)), expected, "{} {}", batch, chunk);
+            }
+        }
+
+        // parsed straight into `f32`, converted like the serial path does
+        let mut file = LineReader::new(io::Cursor::new(grid));
+        let ngrid = ChgBase::_read_ngrid(&mut file, Section::TotalDensity).unwrap();
+        let mut buf = Vec::<f32>::new();
+        ChgBase::_read_values_par(&mut file, Section::TotalDensity, ngrid.iter().product(),
+                                  &ReadOptions::default(), &mut buf, 3.0, (3, 2)).unwrap();
+        let expected: Vec<f32> = serial(grid, &ReadOptions::default()).unwrap().iter()
+            .map(|&v| f32::from_f64(v / 3.0)).collect();
+        assert_eq!(buf, expected);
+
+        // the first error in file order wins, whatever the chunk it is in
+        for &(batch, chunk) in &[(1, 1), (3, 1), (100, 1), (100, 100)] {
+            let err = parallel(&broken, &ReadOptions::default(), batch, chunk).unwrap_err();
+            assert_eq!(err.to_string(), serial(&broken, &ReadOptions::default()).unwrap_err().to_string());
+            let err = parallel(&overflowed, &ReadOptions::default(), batch, chunk).unwrap_err();
+            assert!(matches!(err, ChgError::Overflow { at: Location { line: 3, offset: 108, .. }, .. }));
+            let short = &grid[.. grid.find(" 0.10677009023E+01").unwrap()];
+            assert!(matches!(parallel(short, &ReadOptions::default(), batch, chunk),
+                             Err(ChgError::ShortGrid { expected: 24, found: 15, .. })));
+        }
+
+        let buf: Vec<f64> = (0 .. 24).map(|i| i as f64).collect();
+        let expected = Array3::from_shape_vec((4, 3, 2), buf.clone()).unwrap()
+            .reversed_axes().as_standard_layout().into_owned();
+        assert_eq!(ChgBase::_to_standard_layout([2, 3, 4], buf).unwrap(), expected);
+    }
+
+    #[test]
+    #[cfg(feature = "rayon")]
+    fn test_write_rows_par() {
+        let chg = Array3::from_shape_fn((3, 5, 7), |(x, y, z)| (x as f64 - 1.2) * 10f64.powi((y * z) as i32 - 12));
+        let format = NumberFormat { per_row: 4, ..NumberFormat::default_for(ChgType::Chgcar) };
+        let nrows = chg.len().div_ceil(format.per_row);
+        let mut expected = String::new();
+        ChgBase::_format_rows(&chg, 2.0, &format, 0 .. nrows, &mut expected);
+        assert_eq!(expected.lines().count(), 27);
+        assert!(expected.starts_with(" -.24000000000E-11 -.40000000000E-12 0.16000000000E-11 -.24000000000E-11\n"));
+
+        // chunks smaller than a batch, and a batch over the end of the grid
+        for &(batch, chunk) in [(3, 2), (1, 1), (4, 100)].iter() {
+            let mut out = vec![];
+            ChgBase::_write_rows_par(&mut out, &chg, 2.0, &format, nrows, batch, chunk).unwrap();
+            assert_eq!(String::from_utf8(out).unwrap(), expected, "{} {}", batch, chunk);
+        }
+    }
+
+    #[test]
+    fn test_f32() {
+        let chg64 = ChgBase::from_reader(&mut io::Cursor::new(SAMPLE)).unwrap();
+        let chg32 = ChgBase::<f32>::from_reader_as(&mut io::Cursor::new(SAMPLE), &ReadOptions::default())
+            .unwrap();
+        assert_eq!(chg32.get_ngrid(), chg64.get_ngrid());
+        assert_eq!(chg32.get_total_chg(), &chg64.get_total_chg().mapv(|v| v as f32));
+        assert_eq!(chg32.get_diff_chg()[0], chg64.get_diff_chg()[0].mapv(|v| v as f32));
+        assert_eq!(chg32.get_total_aug(), chg64.get_total_aug());
+        let bytes = ChgBase::<f32>::from_bytes_as(SAMPLE.as_bytes(), &ReadOptions::default()).unwrap();
+        assert_eq!(bytes.get_total_chg(), chg32.get_total_chg());
+
+        // explicit conversions, widening is exact
+        assert_eq!(chg64.convert::<f32>().get_total_chg(), chg32.get_total_chg());
+        assert_eq!(chg32.convert::<f64>().convert::<f32>().get_total_chg(), chg32.get_total_chg());
+
+        // arithmetic works on the f32 grids as usual
+        let mut doubled = chg32.convert::<f32>();
+        *doubled.get_mut_total_chg() *= 2.0;
+        assert_eq!(doubled.get_total_chg()[[1, 2, 3]], chg32.get_total_chg()[[1, 2, 3]] * 2.0);
+
+        // written through f64, the same layout as the f64 grids, within f32 precision
+        let mut out = vec![];
+        chg32.write_writer(&mut out, ChgType::Chgcar).unwrap();
+        let mut out64 = vec![];
+        chg64.write_writer(&mut out64, ChgType::Chgcar).unwrap();
+        assert_eq!(out.len(), out64.len());
+        let reread = ChgBase::from_reader(&mut io::Cursor::new(out)).unwrap();
+        for (a, b) in reread.get_total_chg().iter().zip(chg64.get_total_chg().iter()) {
+            assert!((a - b).abs() <= 1E-6 * b.abs(), "{} {}", a, b);
+        }
+    }
+
+    #[test]
+    fn test_species() {
+        let symbols = |chg: &ChgBase| chg.get_poscar().group_symbols()
+            .map(|s| s.map(str::to_owned).collect::<Vec<_>>());
+        let names = |names: &[&str]| ReadOptions {
+            species: Some(SpeciesSource::Names(names.iter().map(|s| s.to_string()).collect())),
+            ..Default::default()
+        };
+        let vasp4 = SAMPLE.replacen("   Li\n", "", 1);
+
+        let chg = ChgBase::from_reader(&mut io::Cursor::new(&vasp4)).unwrap();
+        assert_eq!(symbols(&chg), None);
+        let chg = ChgBase::from_reader_with(&mut io::Cursor::new(&vasp4), &names(&["Li"])).unwrap();
+        assert_eq!(symbols(&chg), Some(vec!["Li".to_owned()]));
+        assert_eq!(chg.get_total_chg(), ChgBase::from_reader(&mut io::Cursor::new(SAMPLE)).unwrap().get_total_chg());
+
+        // the species line is written back
+        let mut out = vec![];
+        chg.write_writer(&mut out, ChgType::Chgcar).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.lines().nth(5).map(str::trim), Some("Li"));
+        assert_eq!(symbols(&ChgBase::from_reader(&mut io::Cursor::new(out)).unwrap()), Some(vec!["Li".to_owned()]));
+
+        // names in the file are replaced
+        let chg = ChgBase::from_bytes_with(SAMPLE.as_bytes(), &names(&["Na"])).unwrap();
+        assert_eq!(symbols(&chg), Some(vec!["Na".to_owned()]));
+
+        // the header text is not reused for other species
+        for (text, opts) in &[(SAMPLE.to_owned(), names(&["Na"])), (vasp4.clone(), names(&["Li"]))] {
+            let opts = ReadOptions { lossless: true, ..opts.clone() };
+            let chg = ChgBase::from_bytes_with(text.as_bytes(), &opts).unwrap();
+            let mut out = vec![];
+            chg.write_writer(&mut out, ChgType::Chgcar).unwrap();
+            let out = String::from_utf8(out).unwrap();
+            assert_eq!(symbols(&ChgBase::from_bytes(out.as_bytes()).unwrap()), symbols(&chg));
+            assert_eq!(out.lines().nth(5).map(str::trim), symbols(&chg).map(|s| s.concat()).as_deref());
+        }
+
+        let err = ChgBase::from_reader_with(&mut io::Cursor::new(&vasp4), &names(&["Li", "O"])).err().unwrap();
+        assert_eq!(err.to_string(),
+                   "invalid header, 2 species names given for 1 ion counts in header at line 6, byte 149");
+        let opts = ReadOptions { species: Some(SpeciesSource::AdjacentPotcar), ..Default::default() };
+        assert!(ChgBase::from_reader_with(&mut io::Cursor::new(&vasp4), &opts).is_err());
+    }
 }
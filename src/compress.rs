@@ -0,0 +1,265 @@
+#[allow(unused_imports)]
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+
+use crate::error::Result;
+
+/// Compression of the written file, the number is the compression level of each codec.
+///
+/// Each codec requires its cargo feature, see [`ChgBase::from_file`](struct.ChgBase.html#method.from_file).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Plain,
+    /// gzip, level 0 ~ 9
+    Gzip(u32),
+    /// xz, level 0 ~ 9
+    Xz(u32),
+    /// zstd, level 1 ~ 22, 0 means zstd's default
+    Zstd(i32),
+    /// bzip2, level 1 ~ 9
+    Bzip2(u32),
+}
+
+impl Compression {
+    /// Choose the codec from the extension of `path` with default levels:
+    /// `.gz`, `.xz`, `.zst` and `.bz2`. Other extensions give `Compression::Plain`.
+    pub fn from_path(path: &(impl AsRef<Path> + ?Sized)) -> Self {
+        match path.as_ref().extension().and_then(|e| e.to_str()) {
+            Some("gz")  => Compression::Gzip(6),
+            Some("xz")  => Compression::Xz(6),
+            Some("zst") => Compression::Zstd(0),
+            Some("bz2") => Compression::Bzip2(9),
+            _           => Compression::Plain,
+        }
+    }
+}
+
+/// Compression formats recognized by their magic bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Codec {
+    Plain,
+    Gzip,
+    Xz,
+    Zstd,
+    Bzip2,
+}
+
+impl Codec {
+    /// Guess the codec from the first bytes of a stream.
+    pub fn sniff(magic: &[u8]) -> Self {
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            Codec::Gzip
+        } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Codec::Xz
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Codec::Zstd
+        } else if magic.starts_with(b"BZh") {
+            Codec::Bzip2
+        } else {
+            Codec::Plain
+        }
+    }
+}
+
+#[allow(unused_macros)]
+macro_rules! unsupported {
+    ($codec:expr) => {
+        return Err(crate::error::ChgError::UnsupportedCompression($codec))
+    };
+}
+
+/// Wrap `file` with a decompressor chosen by its magic bytes, plain text is passed through.
+pub(crate) fn decompress<'a>(mut file: impl BufRead + 'a) -> Result<Box<dyn BufRead + 'a>> {
+    Ok(match Codec::sniff(file.fill_buf()?) {
+        Codec::Plain => Box::new(file),
+
+        #[cfg(feature = "gzip")]
+        Codec::Gzip  => Box::new(BufReader::new(flate2::bufread::MultiGzDecoder::new(file))),
+        #[cfg(not(feature = "gzip"))]
+        Codec::Gzip  => unsupported!("gzip"),
+
+        #[cfg(feature = "xz")]
+        Codec::Xz    => Box::new(BufReader::new(xz2::bufread::XzDecoder::new_multi_decoder(file))),
+        #[cfg(not(feature = "xz"))]
+        Codec::Xz    => unsupported!("xz"),
+
+        #[cfg(feature = "zstd")]
+        Codec::Zstd  => Box::new(BufReader::new(zstd::stream::read::Decoder::with_buffer(file)?)),
+        #[cfg(not(feature = "zstd"))]
+        Codec::Zstd  => unsupported!("zstd"),
+
+        #[cfg(feature = "bzip2")]
+        Codec::Bzip2 => Box::new(BufReader::new(bzip2::bufread::MultiBzDecoder::new(file))),
+        #[cfg(not(feature = "bzip2"))]
+        Codec::Bzip2 => unsupported!("bzip2"),
+    })
+}
+
+/// Streaming compressor chosen by `Compression`, `finish` must be called to write the trailer.
+pub(crate) enum Encoder<W: Write> {
+    Plain(W),
+    #[cfg(feature = "gzip")]
+    Gzip(flate2::write::GzEncoder<W>),
+    #[cfg(feature = "xz")]
+    Xz(xz2::write::XzEncoder<W>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::write::Encoder<'static, W>),
+    #[cfg(feature = "bzip2")]
+    Bzip2(bzip2::write::BzEncoder<W>),
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn new(file: W, compression: Compression) -> Result<Self> {
+        Ok(match compression {
+            Compression::Plain => Encoder::Plain(file),
+
+            #[cfg(feature = "gzip")]
+            Compression::Gzip(level) => Encoder::Gzip(
+                flate2::write::GzEncoder::new(file, flate2::Compression::new(level))),
+            #[cfg(not(feature = "gzip"))]
+            Compression::Gzip(_) => unsupported!("gzip"),
+
+            #[cfg(feature = "xz")]
+            Compression::Xz(level) => Encoder::Xz(xz2::write::XzEncoder::new(file, level)),
+            #[cfg(not(feature = "xz"))]
+            Compression::Xz(_) => unsupported!("xz"),
+
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(level) => Encoder::Zstd(zstd::stream::write::Encoder::new(file, level)?),
+            #[cfg(not(feature = "zstd"))]
+            Compression::Zstd(_) => unsupported!("zstd"),
+
+            #[cfg(feature = "bzip2")]
+            Compression::Bzip2(level) => Encoder::Bzip2(
+                bzip2::write::BzEncoder::new(file, bzip2::Compression::new(level))),
+            #[cfg(not(feature = "bzip2"))]
+            Compression::Bzip2(_) => unsupported!("bzip2"),
+        })
+    }
+
+    /// Flush the remaining compressed data and return the underlying writer.
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::Plain(mut w) => { w.flush()?; Ok(w) },
+            #[cfg(feature = "gzip")]
+            Encoder::Gzip(w)    => w.finish(),
+            #[cfg(feature = "xz")]
+            Encoder::Xz(w)      => w.finish(),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(w)    => w.finish(),
+            #[cfg(feature = "bzip2")]
+            Encoder::Bzip2(w)   => w.finish(),
+        }
+    }
+
+    fn inner(&mut self) -> &mut dyn Write {
+        match self {
+            Encoder::Plain(w)   => w,
+            #[cfg(feature = "gzip")]
+            Encoder::Gzip(w)    => w,
+            #[cfg(feature = "xz")]
+            Encoder::Xz(w)      => w,
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(w)    => w,
+            #[cfg(feature = "bzip2")]
+            Encoder::Bzip2(w)   => w,
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.inner().write(buf) }
+    fn flush(&mut self) -> io::Result<()> { self.inner().flush() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Read};
+
+    const TEXT: &[u8] = b"unknown system\n   1.00000000000000\n";
+
+    fn roundtrip(compressed: Vec<u8>) -> Vec<u8> {
+        let mut out = vec![];
+        decompress(Cursor::new(compressed)).unwrap().read_to_end(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_sniff() {
+        assert_eq!(Codec::sniff(TEXT), Codec::Plain);
+        assert_eq!(Codec::sniff(b""), Codec::Plain);
+        assert_eq!(Codec::sniff(&[0x1f, 0x8b, 0x08]), Codec::Gzip);
+        assert_eq!(Codec::sniff(b"\xfd7zXZ\x00\x00"), Codec::Xz);
+        assert_eq!(Codec::sniff(&[0x28, 0xb5, 0x2f, 0xfd, 0x00]), Codec::Zstd);
+        assert_eq!(Codec::sniff(b"BZh91AY&SY"), Codec::Bzip2);
+        assert_eq!(roundtrip(TEXT.to_vec()), TEXT);
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_gzip() {
+        let mut enc = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        enc.write_all(TEXT).unwrap();
+        assert_eq!(roundtrip(enc.finish().unwrap()), TEXT);
+    }
+
+    #[test]
+    #[cfg(feature = "xz")]
+    fn test_xz() {
+        let mut enc = xz2::write::XzEncoder::new(vec![], 6);
+        enc.write_all(TEXT).unwrap();
+        assert_eq!(roundtrip(enc.finish().unwrap()), TEXT);
+    }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn test_zstd() {
+        assert_eq!(roundtrip(zstd::encode_all(TEXT, 0).unwrap()), TEXT);
+    }
+
+    #[test]
+    #[cfg(feature = "bzip2")]
+    fn test_bzip2() {
+        let mut enc = bzip2::write::BzEncoder::new(vec![], bzip2::Compression::default());
+        enc.write_all(TEXT).unwrap();
+        assert_eq!(roundtrip(enc.finish().unwrap()), TEXT);
+    }
+
+    fn compress(compression: Compression) -> Vec<u8> {
+        let mut enc = Encoder::new(vec![], compression).unwrap();
+        enc.write_all(TEXT).unwrap();
+        enc.finish().unwrap()
+    }
+
+    #[test]
+    fn test_from_path() {
+        assert_eq!(Compression::from_path("CHGCAR"), Compression::Plain);
+        assert_eq!(Compression::from_path("CHGCAR.vasp"), Compression::Plain);
+        assert_eq!(Compression::from_path("CHGCAR.gz"), Compression::Gzip(6));
+        assert_eq!(Compression::from_path("dir.xz/CHGCAR.xz"), Compression::Xz(6));
+        assert_eq!(Compression::from_path("CHGCAR.zst"), Compression::Zstd(0));
+        assert_eq!(Compression::from_path("CHGCAR.bz2"), Compression::Bzip2(9));
+        assert_eq!(compress(Compression::Plain), TEXT);
+    }
+
+    #[test]
+    #[cfg(all(feature = "gzip", feature = "xz", feature = "zstd", feature = "bzip2"))]
+    fn test_encoder() {
+        for &compression in &[Compression::Gzip(1), Compression::Xz(9),
+                               Compression::Zstd(19), Compression::Bzip2(1)] {
+            let compressed = compress(compression);
+            assert_ne!(compressed, TEXT);
+            assert_eq!(roundtrip(compressed), TEXT);
+        }
+    }
+
+    #[test]
+    #[cfg(not(feature = "xz"))]
+    fn test_unsupported() {
+        let err = decompress(Cursor::new(b"\xfd7zXZ\x00\x00".to_vec())).err().unwrap();
+        assert!(matches!(err, crate::error::ChgError::UnsupportedCompression("xz")));
+        let err = Encoder::new(vec![], Compression::Xz(6)).err().unwrap();
+        assert!(matches!(err, crate::error::ChgError::UnsupportedCompression("xz")));
+    }
+}
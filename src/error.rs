@@ -1,8 +1,158 @@
-#![allow(dead_code)]
-#![allow(unused_imports)]
-
 use std::error::Error;
 use std::fmt;
+use std::io;
+
+use vasp_poscar::failure::Error as PoscarError;
+
+use crate::meta::Component;
+
+/// Errors raised while reading or writing volumetric data.
+#[derive(Debug)]
+pub enum ChgError {
+    /// Underlying I/O failure.
+    Io(io::Error),
+    /// The POSCAR part of the header cannot be parsed by `vasp_poscar`.
+    Poscar(PoscarError),
+    /// The header is malformed before the POSCAR parser could even see it,
+    /// e.g. the file ends before the blank line that closes the header.
+    Header {
+        msg:        String,
+        at:         Location,
+    },
+    /// The `NGX NGY NGZ` line in front of a grid is malformed.
+    BadGridLine {
+        line:       String,
+        at:         Location,
+    },
+    /// A token in the grid or augmentation data is not a number.
+    InvalidNumber {
+        token:      String,
+        at:         Location,
+    },
+    /// The requested density component is not in the file.
+    MissingComponent(Component),
+    /// A CHGCAR is written, but `component` has no augmentation occupancies, see
+    /// [`WriteOptions::zero_aug_lmmax`](struct.WriteOptions.html#structfield.zero_aug_lmmax).
+    MissingAugmentation(Component),
+    /// The file is compressed with a codec whose cargo feature is not enabled.
+    UnsupportedCompression(&'static str),
+    /// A field is filled with `*` because the value overflowed the Fortran field width,
+    /// see [`OverflowPolicy`](enum.OverflowPolicy.html).
+    Overflow {
+        token:      String,
+        at:         Location,
+    },
+    /// The grid data ends before `NGX * NGY * NGZ` values are read.
+    ShortGrid {
+        expected:   usize,
+        found:      usize,
+        at:         Location,
+    },
+}
+
+/// Part of the file being read when an error occurs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Section {
+    /// Lattice, species and positions in front of the first grid.
+    Header,
+    /// The first grid in the file.
+    TotalDensity,
+    /// Augmentation occupancies following the total density, `block` is the atom index
+    /// in the `augmentation occupancies <block> <n>` line.
+    TotalAugmentation {
+        block:      usize,
+    },
+    /// The `component`-th grid after the total density, i.e. `get_diff_chg()[component]`.
+    DiffDensity {
+        component:  usize,
+    },
+    /// Augmentation occupancies following `DiffDensity { component }`.
+    DiffAugmentation {
+        component:  usize,
+        block:      usize,
+    },
+}
+
+/// Position of a parse failure in the input stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location {
+    pub section:    Section,
+    /// 1-based line number.
+    pub line:       usize,
+    /// Byte offset from the beginning of the stream, pointing to the offending token if any.
+    pub offset:     u64,
+}
+
+pub(crate) type Result<T> = std::result::Result<T, ChgError>;
+
+impl fmt::Display for ChgError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChgError::Io(e)             => write!(f, "I/O error: {}", e),
+            ChgError::Poscar(e)         => write!(f, "invalid POSCAR header: {}", e),
+            ChgError::Header { msg, at } => write!(f, "invalid header, {} {}", msg, at),
+            ChgError::BadGridLine { line, at } =>
+                write!(f, "invalid grid dimension line {:?} {}", line, at),
+            ChgError::InvalidNumber { token, at } =>
+                write!(f, "invalid number {:?} {}", token, at),
+            ChgError::Overflow { token, at } =>
+                write!(f, "overflowed Fortran field {:?} {}", token, at),
+            ChgError::MissingComponent(component) =>
+                write!(f, "no density component {:?} in the file", component),
+            ChgError::MissingAugmentation(component) =>
+                write!(f, "no augmentation occupancies for {:?}, cannot write a CHGCAR", component),
+            ChgError::UnsupportedCompression(codec) =>
+                write!(f, "{0}-compressed input found, enable feature `{0}` to read it", codec),
+            ChgError::ShortGrid { expected, found, at } =>
+                write!(f, "grid data too short, expected {} values but found {} {}",
+                       expected, found, at),
+        }
+    }
+}
+
+impl fmt::Display for Section {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Section::Header => write!(f, "header"),
+            Section::TotalDensity => write!(f, "total density"),
+            Section::TotalAugmentation { block } =>
+                write!(f, "augmentation block {} of total density", block),
+            Section::DiffDensity { component } => write!(f, "diff component {}", component),
+            Section::DiffAugmentation { component, block } =>
+                write!(f, "augmentation block {} of diff component {}", block, component),
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "in {} at line {}, byte {}", self.section, self.line, self.offset)
+    }
+}
+
+impl Error for ChgError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ChgError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ChgError {
+    fn from(e: io::Error) -> Self { ChgError::Io(e) }
+}
+
+impl From<PoscarError> for ChgError {
+    fn from(e: PoscarError) -> Self { ChgError::Poscar(e) }
+}
 
-// TODO: discriminate parse failure due to fortran's fucking exceeded length and
-//       optional part loss, now I treat all failure as lacking optional part
+/// Allows `?` on `ChgBase` methods inside functions returning `std::io::Result`.
+impl From<ChgError> for io::Error {
+    fn from(e: ChgError) -> Self {
+        match e {
+            ChgError::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
@@ -0,0 +1,26 @@
+use std::fmt;
+
+use ndarray::{LinalgScalar, ScalarOperand};
+
+/// Float type the grids of [`ChgBase`](struct.ChgBase.html) are stored in, implemented for
+/// `f64` and `f32`.
+///
+/// The text of a CHGCAR holds about 11 significant digits, numbers are always parsed and
+/// written through `f64`, so `f32` grids only lose precision once, when they are stored.
+pub trait ChgFloat: LinalgScalar + ScalarOperand + PartialOrd + fmt::Debug + fmt::Display
+    + fmt::UpperExp + Send + Sync {
+    /// Round `v` to the nearest value of this type.
+    fn from_f64(v: f64) -> Self;
+    /// Widen to `f64`, this is exact.
+    fn to_f64(self) -> f64;
+}
+
+impl ChgFloat for f64 {
+    fn from_f64(v: f64) -> Self { v }
+    fn to_f64(self) -> f64      { self }
+}
+
+impl ChgFloat for f32 {
+    fn from_f64(v: f64) -> Self { v as f32 }
+    fn to_f64(self) -> f64      { self as f64 }
+}
@@ -0,0 +1,323 @@
+//! Tokenizer and parser for numbers written by Fortran formatted output.
+//!
+//! Fortran fills a field with `*` when a value does not fit its width, and glues two fields
+//! together when the first one fills its width completely, e.g. `-0.123E+01-0.456E+01`.
+//! `str::split_ascii_whitespace` handles neither of them.
+
+use std::fmt::{self, Write};
+
+/// A field in a line of numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Token<'a> {
+    /// Something that looks like a number, it may still fail to parse.
+    Number(&'a str),
+    /// A run of `*`, which Fortran writes when the value overflows the field width.
+    Overflow(&'a str),
+}
+
+/// Iterator over the fields of a line, yields the byte index of each field and the field.
+pub(crate) struct Tokens<'a> {
+    line:   &'a str,
+    pos:    usize,
+}
+
+/// Split `line` into fields, glued numbers are split at the sign of the second number.
+pub(crate) fn tokens(line: &str) -> Tokens<'_> {
+    Tokens { line, pos: 0 }
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = (usize, Token<'a>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let bytes = self.line.as_bytes();
+        let start = self.pos + bytes[self.pos ..].iter().take_while(|c| c.is_ascii_whitespace()).count();
+        if start == bytes.len() {
+            self.pos = start;
+            return None;
+        }
+
+        if bytes[start] == b'*' {
+            let end = start + bytes[start ..].iter().take_while(|&&c| c == b'*').count();
+            self.pos = end;
+            return Some((start, Token::Overflow(&self.line[start .. end])));
+        }
+
+        let mut end = start + scan_number(&bytes[start ..]).1;
+        let at_boundary = end > start && bytes.get(end)
+            .is_none_or(|&c| c.is_ascii_whitespace() || c == b'+' || c == b'-' || c == b'*');
+        if !at_boundary {
+            // not a number, take the whole word and let the parser complain about it
+            end += bytes[end ..].iter().take_while(|c| !c.is_ascii_whitespace()).count();
+        }
+        self.pos = end;
+        Some((start, Token::Number(&self.line[start .. end])))
+    }
+}
+
+/// Parse a Fortran real.
+///
+/// On top of what `str::parse::<f64>` accepts, this takes `D` exponents (`0.1234D+01`) and
+/// exponents without a letter (`0.1234-105`), which Fortran writes when the exponent has three
+/// digits and the `E` no longer fits.
+pub(crate) fn parse_real(s: &str) -> Option<f64> {
+    if let Ok(v) = s.parse::<f64>() {
+        return Some(v);
+    }
+
+    let (mantissa, len) = scan_number(s.as_bytes());
+    if len != s.len() || mantissa == len {
+        return None;
+    }
+    let (mantissa, exponent) = s.split_at(mantissa);
+    let exponent = match exponent.as_bytes()[0] {
+        b'D' | b'd' | b'E' | b'e' => &exponent[1 ..],
+        _ => exponent,
+    };
+    format!("{}E{}", mantissa, exponent).parse::<f64>().ok()
+}
+
+/// Append `v` to `out` formatted like Fortran `Ew.d` editing with `width` and `digits`
+/// significant digits, e.g. `0.44062E+00` for `E11.5`, right aligned.
+///
+/// As gfortran does, the leading zero is dropped if the field is too narrow for it, e.g.
+/// `-.27330E-02` for `E11.5`, exponents with three digits drop the `E`, e.g. `0.12345-100`,
+/// and a field still too narrow is filled with `*`. All of them are read back by `parse_real`.
+///
+/// Nothing is allocated unless `digits` is unusually large, this runs for every grid value.
+pub(crate) fn write_real(out: &mut String, v: f64, digits: usize, width: usize) {
+    if !v.is_finite() {
+        let text = match (v.is_nan(), v > 0.0) {
+            (true, _) => "NaN",
+            (false, true) => if width >= 8 { "Infinity" } else { "Inf" },
+            (false, false) => if width >= 9 { "-Infinity" } else { "-Inf" },
+        };
+        return pad(out, text, width);
+    }
+
+    // Rust writes `d.ddddde<exp>`, Fortran `0.dddddE<exp + 1>`
+    let mut scratch = Scratch { buf: [0; 48], len: 0 };
+    let heap;
+    let sci = match write!(scratch, "{:.*e}", digits - 1, v.abs()) {
+        Ok(()) => scratch.as_str(),
+        Err(_) => {
+            heap = format!("{:.*e}", digits - 1, v.abs());
+            heap.as_str()
+        },
+    };
+    let (mantissa, exponent) = sci.split_at(sci.find('e').expect("exponent is always written"));
+    let exponent = if v == 0.0 {
+        0
+    } else {
+        exponent[1 ..].parse::<i32>().expect("exponent is an integer") + 1
+    };
+
+    let negative = v.is_sign_negative();
+    let mut len = negative as usize + 2 + digits + 4;    // sign, `0.`, digits and `E+dd` or `+ddd`
+    let zero = len <= width;
+    if !zero {
+        len -= 1;
+    }
+    if len > width {
+        return out.extend(std::iter::repeat_n('*', width));
+    }
+    out.extend(std::iter::repeat_n(' ', width - len));
+    if negative {
+        out.push('-');
+    }
+    out.push_str(if zero { "0." } else { "." });
+    out.extend(mantissa.chars().filter(|&c| c != '.'));
+    let abs = exponent.unsigned_abs();
+    if abs < 100 {
+        out.push('E');
+    }
+    out.push(if exponent < 0 { '-' } else { '+' });
+    if abs >= 100 {
+        out.push(char::from(b'0' + (abs / 100) as u8));
+    }
+    out.push(char::from(b'0' + (abs / 10 % 10) as u8));
+    out.push(char::from(b'0' + (abs % 10) as u8));
+}
+
+/// Right align `text` in a field of `width`, or fill the field with `*` if it is too narrow.
+fn pad(out: &mut String, text: &str, width: usize) {
+    if text.len() > width {
+        out.extend(std::iter::repeat_n('*', width));
+    } else {
+        out.extend(std::iter::repeat_n(' ', width - text.len()));
+        out.push_str(text);
+    }
+}
+
+/// Fixed buffer on the stack for formatting a single number.
+struct Scratch {
+    buf:    [u8; 48],
+    len:    usize,
+}
+
+impl Scratch {
+    fn as_str(&self) -> &str {
+        std::str::from_utf8(&self.buf[.. self.len]).expect("only ASCII is written")
+    }
+}
+
+impl fmt::Write for Scratch {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        self.buf.get_mut(self.len .. end).ok_or(fmt::Error)?.copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// Scan the longest prefix of `s` that is a number, returns the length of the mantissa and the
+/// length of the whole number. Accepted forms are `[+-]digits[.digits][(E|D)[+-]digits]` and
+/// `[+-]digits[.digits](+|-)digits`, the latter only if the exponent is not followed by a `.`,
+/// otherwise it is the sign of the next glued number.
+fn scan_number(s: &[u8]) -> (usize, usize) {
+    let digits = |from: usize| s[from ..].iter().take_while(|c| c.is_ascii_digit()).count();
+
+    let mut i = 0;
+    if let Some(b'+') | Some(b'-') = s.first() {
+        i += 1;
+    }
+    let int = digits(i);
+    i += int;
+    let mut frac = 0;
+    if s.get(i) == Some(&b'.') {
+        frac = digits(i + 1);
+        i += 1 + frac;
+    }
+    if int + frac == 0 {
+        return (0, 0);
+    }
+
+    let mantissa = i;
+    match s.get(i) {
+        Some(b'E') | Some(b'e') | Some(b'D') | Some(b'd') => {
+            let sign = match s.get(i + 1) {
+                Some(b'+') | Some(b'-') => 1,
+                _ => 0,
+            };
+            let exp = digits(i + 1 + sign);
+            if exp > 0 {
+                i += 1 + sign + exp;
+            }
+        },
+        Some(b'+') | Some(b'-') => {
+            let exp = digits(i + 1);
+            if exp > 0 && s.get(i + 1 + exp) != Some(&b'.') {
+                i += 1 + exp;
+            }
+        },
+        _ => {},
+    }
+    (mantissa, i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(line: &str) -> Vec<Token<'_>> {
+        tokens(line).map(|(_, t)| t).collect()
+    }
+
+    #[test]
+    fn test_tokens() {
+        use Token::*;
+        assert_eq!(fields(" 0.44062142953E+00 -.27330404989E-02\r\n"),
+                   vec![Number("0.44062142953E+00"), Number("-.27330404989E-02")]);
+        assert_eq!(fields("-0.123E+01-0.456E+01 0.1E+01+0.2E+01"),
+                   vec![Number("-0.123E+01"), Number("-0.456E+01"),
+                        Number("0.1E+01"), Number("+0.2E+01")]);
+        assert_eq!(fields(" 0.1E+01***** ******0.2E+01"),
+                   vec![Number("0.1E+01"), Overflow("*****"), Overflow("******"), Number("0.2E+01")]);
+        assert_eq!(fields("  1  2 30"), vec![Number("1"), Number("2"), Number("30")]);
+        assert_eq!(fields("0.48881O56285E+00 abc ."),
+                   vec![Number("0.48881O56285E+00"), Number("abc"), Number(".")]);
+        assert_eq!(fields("   \n"), vec![]);
+        assert_eq!(tokens("  0.1-0.2 ***").map(|(i, _)| i).collect::<Vec<_>>(), vec![2, 5, 10]);
+    }
+
+    #[test]
+    fn test_tokens_exponent_forms() {
+        use Token::*;
+        assert_eq!(fields("0.1234D+01-0.1234-105 0.1234+105-0.5E-01"),
+                   vec![Number("0.1234D+01"), Number("-0.1234-105"),
+                        Number("0.1234+105"), Number("-0.5E-01")]);
+        assert_eq!(fields("0.1234-0.5678 0.1234-.5"),
+                   vec![Number("0.1234"), Number("-0.5678"), Number("0.1234"), Number("-.5")]);
+    }
+
+    fn format_real(v: f64, digits: usize, width: usize) -> String {
+        let mut out = String::new();
+        write_real(&mut out, v, digits, width);
+        out
+    }
+
+    #[test]
+    fn test_format_real() {
+        let cases = [
+            (0.44062142953,         "0.44062E+00"),
+            (-0.27330404989E-02,    "-.27330E-02"),
+            (1.0000382501,          "0.10000E+01"),
+            (0.999996,              "0.10000E+01"),
+            (12340.0,               "0.12340E+05"),
+            (0.0,                   "0.00000E+00"),
+            (0.1234E-105,           "0.12340-105"),
+            (-0.1234E+100,          "-.12340+100"),
+        ];
+        for &(v, s) in cases.iter() {
+            assert_eq!(format_real(v, 5, 11), s, "{}", v);
+            assert!((parse_real(s).unwrap() - v).abs() <= v.abs() * 1E-4, "{}", s);
+        }
+        assert_eq!(format_real(0.44062142953, 11, 17), "0.44062142953E+00");
+        assert_eq!(format_real(-0.44062142953, 11, 17), "-.44062142953E+00");
+        assert_eq!(format_real(-0.44062142953, 11, 19), " -0.44062142953E+00");
+        assert_eq!(format_real(-0.44062142953, 5, 9), "*********");
+        assert_eq!(format_real(f64::NAN, 5, 11), "        NaN");
+        assert_eq!(format_real(f64::NEG_INFINITY, 5, 11), "  -Infinity");
+        assert_eq!(format_real(f64::INFINITY, 5, 5), "  Inf");
+        assert_eq!(format_real(f64::NAN, 5, 2), "**");
+        assert_eq!(format_real(0.1, 1, 7), "0.1E+00");
+        // too long for the stack buffer
+        let long = format_real(1.0 / 3.0, 50, 56);
+        assert_eq!(long, "0.33333333333333331482961625624739099293947219848633E+00");
+    }
+
+    #[test]
+    fn test_parse_real() {
+        let cases = [
+            // plain Rust/C forms
+            ("0.44062142953E+00",   0.44062142953),
+            ("-.27330404989E-02",   -0.27330404989E-02),
+            ("5.09252025678E+00",   5.09252025678),
+            ("1.5",                 1.5),
+            ("-3",                  -3.0),
+            ("+.5",                 0.5),
+            ("7.",                  7.0),
+            ("0.1234e-5",           0.1234E-5),
+            // D exponents
+            ("0.1234D+01",          1.234),
+            ("0.1234d-01",          0.01234),
+            ("-0.1234D01",          -1.234),
+            ("0.1234D5",            12340.0),
+            // E dropped for 3-digit exponents
+            ("0.1234-105",          0.1234E-105),
+            ("0.1234+105",          0.1234E+105),
+            ("-0.1234-105",         -0.1234E-105),
+            ("-.1234+100",          -0.1234E+100),
+            ("0.9999-300",          0.9999E-300),
+            ("1.0+1",               10.0),
+        ];
+        for &(s, v) in cases.iter() {
+            assert_eq!(parse_real(s), Some(v), "{}", s);
+        }
+
+        for s in ["", ".", "-", "E+01", "0.1E", "0.1D+", "0.1-", "0.1-0.2", "0.1E+01-05",
+                  "0.48881O56285E+00", "1.0+1.0", "abc"].iter() {
+            assert_eq!(parse_real(s), None, "{}", s);
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use std::cell::{OnceCell, RefCell};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+
+use ndarray::Array3;
+use vasp_poscar::Poscar;
+
+use crate::base::ChgBase;
+use crate::compress;
+use crate::error::{ChgError, Result};
+use crate::float::ChgFloat;
+use crate::meta::{ChgMeta, Component};
+use crate::options::ReadOptions;
+use crate::reader::LineReader;
+use crate::species;
+
+/// Volumetric data whose diff components are parsed only when they are first accessed.
+///
+/// The total density and its augmentation occupancies are read on construction, the
+/// magnetization grids of spin-polarized and non-collinear files are skipped over and parsed
+/// from the kept file handle on demand, then cached. Plain files are seeked to the component
+/// directly, compressed files have to be decompressed up to it again.
+///
+/// ```no_run
+/// use vaspchg_rs::LazyChg;
+///
+/// let chg = LazyChg::from_file("CHGCAR").unwrap();
+/// let total = chg.get_total_chg();             // already parsed
+/// let mz = chg.get_diff_chg(2).unwrap();       // parsed now
+/// ```
+///
+/// The grids are parsed into `T`, see [`from_file_as`](#method.from_file_as).
+///
+/// `ReadOptions::lossless` and `ReadOptions::provenance` are not supported, as the text and
+/// the hash would need the whole file read up front, and the provenance sidecar of the file is
+/// not read, so `into_chgbase` starts with an empty log.
+///
+/// The cache is filled through `&self`, so `LazyChg` is not `Sync`.
+pub struct LazyChg<T = f64> {
+    file:       RefCell<File>,
+    meta:       ChgMeta,
+    opts:       ReadOptions,
+    chg:        Array3<T>,
+    aug:        Option<String>,
+    magmom:     String,
+
+    chgdiff:    Vec<OnceCell<Array3<T>>>,
+    augdiff:    Vec<OnceCell<(String, String)>>,    // occupancies and magnetic moments
+}
+
+impl LazyChg {
+    pub fn from_file(path: &(impl AsRef<Path> + ?Sized)) -> Result<Self> {
+        Self::from_file_with(path, &ReadOptions::default())
+    }
+
+    /// Open `path`, read the total density and locate the diff components.
+    ///
+    /// It is an error of kind `InvalidInput` to set `opts.lossless` or `opts.provenance`.
+    pub fn from_file_with(path: &(impl AsRef<Path> + ?Sized), opts: &ReadOptions) -> Result<Self> {
+        Self::from_file_as(path, opts)
+    }
+}
+
+impl<T: ChgFloat> LazyChg<T> {
+    /// Open `path` like `from_file_with`, the grids are parsed into `T`, e.g. `f32` to halve
+    /// the memory.
+    pub fn from_file_as(path: &(impl AsRef<Path> + ?Sized), opts: &ReadOptions) -> Result<Self> {
+        if opts.lossless || opts.provenance {
+            let msg = "LazyChg does not support ReadOptions::lossless and ReadOptions::provenance";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg).into());
+        }
+        let species = species::resolve(opts, Some(path.as_ref()))?;
+        let mut file = File::open(path)?;
+        let reader = compress::decompress(BufReader::new(&mut file))?;
+        let meta = ChgBase::_scan(&mut LineReader::new(reader), species.as_deref())?;
+        let chg = ChgBase::_read_grid_from(&mut file, &meta, Component::Total, opts)?;
+        let (aug, magmom) = ChgBase::_read_aug_from(&mut file, &meta, Component::Total, opts)?;
+        let ndiff = meta.get_ncomponents() - 1;
+        Ok(Self {
+            file: RefCell::new(file),
+            meta,
+            opts: opts.clone(),
+            chg,
+            aug,
+            magmom,
+            chgdiff: (0 .. ndiff).map(|_| OnceCell::new()).collect(),
+            augdiff: (0 .. ndiff).map(|_| OnceCell::new()).collect(),
+        })
+    }
+
+    pub fn get_poscar(&self) -> &Poscar         { self.meta.get_poscar() }
+    pub fn get_ngrid(&self) -> &[usize; 3]      { self.meta.get_ngrid() }
+    pub fn get_meta(&self) -> &ChgMeta          { &self.meta }
+    pub fn get_total_chg(&self) -> &Array3<T>   { &self.chg }
+    pub fn get_total_aug(&self) -> Option<&String> { self.aug.as_ref() }
+
+    /// Number of diff components, 0 for ISPIN = 1.
+    pub fn get_ndiff(&self) -> usize            { self.chgdiff.len() }
+
+    /// The `k`-th diff component, parsed on the first call.
+    pub fn get_diff_chg(&self, k: usize) -> Result<&Array3<T>> {
+        let cell = self.chgdiff.get(k).ok_or(ChgError::MissingComponent(Component::Diff(k)))?;
+        if let Some(chg) = cell.get() {
+            return Ok(chg);
+        }
+        let file = &mut *self.file.borrow_mut();
+        let chg = ChgBase::_read_grid_from(file, &self.meta, Component::Diff(k), &self.opts)?;
+        Ok(cell.get_or_init(|| chg))
+    }
+
+    /// Augmentation occupancies of the `k`-th diff component, read on the first call.
+    pub fn get_diff_aug(&self, k: usize) -> Result<&String> {
+        let cell = self.augdiff.get(k).ok_or(ChgError::MissingComponent(Component::Diff(k)))?;
+        if let Some((aug, _)) = cell.get() {
+            return Ok(aug);
+        }
+        let file = &mut *self.file.borrow_mut();
+        let component = Component::Diff(k);
+        let (aug, magmom) = ChgBase::_read_aug_from(file, &self.meta, component, &self.opts)?;
+        Ok(&cell.get_or_init(|| (aug.unwrap_or_default(), magmom)).0)
+    }
+
+    /// Whether the `k`-th diff component was parsed already.
+    pub fn is_diff_loaded(&self, k: usize) -> bool {
+        self.chgdiff.get(k).is_some_and(|cell| cell.get().is_some())
+    }
+
+    /// Parse the remaining diff components and convert to `ChgBase`.
+    pub fn into_chgbase(self) -> Result<ChgBase<T>> {
+        for k in 0 .. self.get_ndiff() {
+            self.get_diff_chg(k)?;
+            self.get_diff_aug(k)?;
+        }
+        let chgdiff = self.chgdiff.into_iter()
+            .map(|cell| cell.into_inner().expect("parsed above"))
+            .collect();
+        let (augdiff, magdiff): (Vec<_>, Vec<_>) = self.augdiff.into_iter()
+            .map(|cell| cell.into_inner().expect("parsed above"))
+            .unzip();
+        let magmom = Some(self.magmom).into_iter().chain(magdiff).collect();
+        Ok(ChgBase::_from_parts(&self.meta, self.chg, self.aug, chgdiff, augdiff, magmom))
+    }
+}
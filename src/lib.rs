@@ -29,7 +29,27 @@
 //! Detailed usage could be found in ChgBase and unit tests in `tests/` folder
 
 mod error;
+mod compress;
+mod atomic;
+mod float;
+mod fortran;
+mod reader;
+mod options;
+mod species;
+mod meta;
+mod lossless;
+mod provenance;
 mod base;
+mod lazy;
+mod writer;
 
 pub use base::ChgType;
-pub use base::ChgBase;
\ No newline at end of file
+pub use base::ChgBase;
+pub use lazy::LazyChg;
+pub use writer::ChgWriter;
+pub use float::ChgFloat;
+pub use error::{ChgError, Location, Section};
+pub use compress::Compression;
+pub use options::{ReadOptions, OverflowPolicy, SpeciesSource, WriteOptions, NumberFormat, FloatStyle};
+pub use meta::{ChgMeta, Component, ComponentOffsets, SectionStart};
+pub use provenance::{Provenance, SourceFile};
@@ -0,0 +1,101 @@
+//! Original text kept by `ReadOptions::lossless`, so that unmodified parts of a file are written
+//! back byte for byte.
+//!
+//! Whether a part is modified is decided by a fingerprint taken when the file is read: the
+//! header by the parsed `Poscar`, a grid by the bits of its values and the volume it is
+//! multiplied by on write.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
+
+use ndarray::Array3;
+use vasp_poscar::Poscar;
+
+use crate::float::ChgFloat;
+
+/// Original text of a file and the fingerprints of what was parsed from it.
+#[derive(Clone)]
+pub(crate) struct Source {
+    text:       String,
+    header:     Option<u64>,    // `None` if the header text is not to be reused
+    grids:      Vec<GridSource>,
+}
+
+#[derive(Clone)]
+struct GridSource {
+    grid:       Range<usize>,   // `NGX NGY NGZ` line and the values
+    rest:       Range<usize>,   // augmentation occupancies and blank lines up to the next grid
+    key:        u64,
+}
+
+impl Source {
+    /// `grids` are the byte ranges of the grids in `text`, `keys` their fingerprints.
+    pub fn new(text: String, header: Option<u64>, grids: &[Range<usize>], keys: Vec<u64>) -> Self {
+        let ends = grids.iter().skip(1).map(|g| g.start).chain(Some(text.len()));
+        let grids = grids.iter().zip(ends).zip(keys)
+            .map(|((grid, end), key)| GridSource { grid: grid.clone(), rest: grid.end .. end, key })
+            .collect();
+        Self { text, header, grids }
+    }
+
+    /// Number of grids in the file.
+    pub fn ngrids(&self) -> usize { self.grids.len() }
+
+    /// Text of the header, `None` if the header changed.
+    pub fn header(&self, key: u64) -> Option<&str> {
+        let end = self.grids.first().map_or(self.text.len(), |g| g.grid.start);
+        Some(&self.text[.. end]).filter(|_| Some(key) == self.header)
+    }
+
+    /// Text of the `k`-th grid, `None` if the grid changed.
+    pub fn grid(&self, k: usize, key: u64) -> Option<&str> {
+        let grid = &self.grids[k];
+        Some(&self.text[grid.grid.clone()]).filter(|_| key == grid.key)
+    }
+
+    /// Text between the `k`-th grid and the next one, or the end of file.
+    pub fn rest(&self, k: usize) -> &str {
+        &self.text[self.grids[k].rest.clone()]
+    }
+}
+
+/// Fingerprint of the header.
+pub(crate) fn header_key(pos: &Poscar, axis_scales: Option<[f64; 3]>) -> u64 {
+    // `Poscar` is not `Hash`, but its `Debug` output prints every float exactly
+    let mut hasher = DefaultHasher::new();
+    format!("{:?}", pos).hash(&mut hasher);
+    axis_scales.map(|s| s.map(f64::to_bits)).hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Fingerprint of a grid that is multiplied by `volume` on write.
+pub(crate) fn grid_key<T: ChgFloat>(chg: &Array3<T>, volume: f64) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    chg.shape().hash(&mut hasher);
+    volume.to_bits().hash(&mut hasher);
+    chg.iter().for_each(|v| v.to_f64().to_bits().hash(&mut hasher));
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_source() {
+        let text = "header\n\n 1 1 1\n 1.0\naug\n 1 1 1\n 2.0\n\n";
+        let chg = Array3::<f64>::zeros((1, 1, 1));
+        let source = Source::new(text.to_owned(), Some(1), &[8 .. 20, 24 .. 36], vec![2, 3]);
+        assert_eq!(source.ngrids(), 2);
+        assert_eq!(source.header(1), Some("header\n\n"));
+        assert_eq!(source.header(0), None);
+        assert_eq!((source.grid(0, 2), source.rest(0)), (Some(" 1 1 1\n 1.0\n"), "aug\n"));
+        assert_eq!((source.grid(1, 3), source.rest(1)), (Some(" 1 1 1\n 2.0\n"), "\n"));
+        assert_eq!(source.grid(1, 2), None);
+
+        assert_eq!(grid_key(&chg, 1.0), grid_key(&chg.clone(), 1.0));
+        assert_ne!(grid_key(&chg, 1.0), grid_key(&chg, 2.0));
+        assert_ne!(grid_key(&chg, 1.0), grid_key(&(chg.clone() + 1.0), 1.0));
+    }
+}
@@ -0,0 +1,101 @@
+use vasp_poscar::Poscar;
+
+use crate::base::ChgType;
+
+/// Density component of a volumetric data file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Component {
+    /// The total density, i.e. `ChgBase::get_total_chg()`.
+    Total,
+    /// The `k`-th grid after the total density, i.e. `ChgBase::get_diff_chg()[k]`.
+    Diff(usize),
+}
+
+impl Component {
+    /// Position of the component in the file, 0 for the total density.
+    pub(crate) fn index(self) -> usize {
+        match self {
+            Component::Total => 0,
+            Component::Diff(k) => k + 1,
+        }
+    }
+
+    /// The component at position `index` in the file, the inverse of `index`.
+    pub(crate) fn at(index: usize) -> Self {
+        match index {
+            0 => Component::Total,
+            k => Component::Diff(k - 1),
+        }
+    }
+}
+
+/// Where a section of the file begins.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SectionStart {
+    /// Byte offset from the beginning of the (decompressed) text.
+    pub offset: u64,
+    /// 1-based line number.
+    pub line:   usize,
+}
+
+/// Where the sections belonging to one density component begin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ComponentOffsets {
+    /// The `NGX NGY NGZ` line in front of the grid.
+    pub grid:   SectionStart,
+    /// The first line of the augmentation occupancies, `None` if there are none.
+    pub aug:    Option<SectionStart>,
+    /// The first line of the per-ion magnetic moments after the grid and its occupancies,
+    /// `None` if there are none.
+    pub magmom: Option<SectionStart>,
+}
+
+/// Header and layout of a volumetric data file, see
+/// [`ChgBase::scan_metadata`](struct.ChgBase.html#method.scan_metadata).
+#[derive(Clone)]
+pub struct ChgMeta {
+    pos:        Poscar,
+    axis_scales: Option<[f64; 3]>,
+    chgtype:    ChgType,
+    ngrid:      [usize; 3],
+    sections:   Vec<ComponentOffsets>,
+}
+
+impl ChgMeta {
+    pub(crate) fn new(pos: Poscar, axis_scales: Option<[f64; 3]>, chgtype: ChgType,
+                      ngrid: [usize; 3], sections: Vec<ComponentOffsets>) -> Self {
+        Self { pos, axis_scales, chgtype, ngrid, sections }
+    }
+
+    pub fn get_poscar(&self) -> &Poscar         { &self.pos }
+
+    /// Per-axis scales of the scaling factor line, see
+    /// [`ChgBase::get_axis_scales`](struct.ChgBase.html#method.get_axis_scales).
+    pub fn get_axis_scales(&self) -> Option<[f64; 3]> { self.axis_scales }
+
+    /// Layout of the file: CHGCAR if there are augmentation occupancies, CHG if the grids have
+    /// 10 values per row, PARCHG otherwise.
+    pub fn get_chgtype(&self) -> ChgType        { self.chgtype }
+
+    /// Shape of the grids.
+    pub fn get_ngrid(&self) -> &[usize; 3]      { &self.ngrid }
+
+    /// Number of density grids, including the total density: 1 for ISPIN = 1, 2 for ISPIN = 2
+    /// and 4 for non-collinear calculations.
+    pub fn get_ncomponents(&self) -> usize      { self.sections.len() }
+
+    /// Whether augmentation occupancies follow the total density, i.e. the file is a CHGCAR.
+    pub fn has_aug(&self) -> bool               { self.sections[0].aug.is_some() }
+
+    pub fn is_spin_polarized(&self) -> bool     { self.get_ncomponents() == 2 }
+    pub fn is_noncollinear(&self) -> bool       { self.get_ncomponents() == 4 }
+
+    /// Where the sections of the total density (index 0) and each diff component (index `k + 1`)
+    /// begin.
+    pub fn get_offsets(&self) -> &[ComponentOffsets] { &self.sections }
+
+    /// Where the sections of `component` begin, `None` if the file has no such component.
+    pub fn get_component_offsets(&self, component: Component) -> Option<&ComponentOffsets> {
+        self.sections.get(component.index())
+    }
+}
@@ -0,0 +1,181 @@
+use std::fmt::Write;
+use std::io;
+use std::path::PathBuf;
+
+use crate::base::ChgType;
+use crate::compress::Compression;
+use crate::fortran;
+
+/// What to do with a field that Fortran filled with `*` because the value overflowed its width.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OverflowPolicy {
+    /// Fail with `ChgError::Overflow`.
+    #[default]
+    Error,
+    /// Read the field as `NaN`.
+    NaN,
+    /// Read the field as the given value.
+    Clamp(f64),
+}
+
+/// Where to take the species names from, for headers without the element symbol line as written
+/// by VASP 4, or to replace the names in the file.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SpeciesSource {
+    /// The names in the order of the ion counts.
+    Names(Vec<String>),
+    /// The `TITEL` lines of the given POTCAR, or its `VRHFIN` lines if there are none.
+    Potcar(PathBuf),
+    /// The POTCAR in the directory of the file being read, only works for files read by path.
+    AdjacentPotcar,
+}
+
+/// Options of [`ChgBase::from_file_with`](struct.ChgBase.html#method.from_file_with) and
+/// [`ChgBase::from_reader_with`](struct.ChgBase.html#method.from_reader_with).
+///
+/// ```
+/// use vaspchg_rs::{ReadOptions, OverflowPolicy};
+///
+/// let opts = ReadOptions {
+///     overflow: OverflowPolicy::Clamp(1.0E+100),
+///     ..Default::default()
+/// };
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ReadOptions {
+    /// Policy for `****` fields in the grids and augmentation occupancies.
+    ///
+    /// The augmentation occupancies are kept as raw text, so overflowed fields are kept
+    /// verbatim there unless the policy is `OverflowPolicy::Error`.
+    pub overflow:       OverflowPolicy,
+
+    /// Species names to put in the header, `None` keeps what is in the file.
+    ///
+    /// The names are written back by the writers, so a VASP 4 file comes out with the species
+    /// line. It is an error if the number of names differs from the number of ion counts.
+    pub species:        Option<SpeciesSource>,
+
+    /// Keep the original text, so that an unmodified `ChgBase` is written back byte for byte,
+    /// see [`ChgBase::write_writer_with`](struct.ChgBase.html#method.write_writer_with).
+    ///
+    /// The whole text is held in memory next to the parsed grids. The header is formatted again
+    /// if `species` is given.
+    pub lossless:       bool,
+
+    /// Record the file read by `from_file_with` and the like in the provenance log with its
+    /// SHA-256 even if it has no sidecar, see
+    /// [`ChgBase::get_provenance`](struct.ChgBase.html#method.get_provenance).
+    ///
+    /// The whole file is read for the hash, also what the parser does not need. Requires the
+    /// `json` feature, reading with it set is an error of kind `Unsupported` otherwise.
+    pub provenance:     bool,
+}
+
+/// How the numbers of a grid are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FloatStyle {
+    /// Fortran `E` editing as VASP writes it, e.g. `0.44062142953E+00` and `-.27330404989E-02`.
+    Fortran,
+    /// Rust's `{:E}`, e.g. `4.4062142953E-1`, as written by earlier versions of this crate.
+    Rust,
+}
+
+/// Number formatting of the grids, see [`WriteOptions::format`](struct.WriteOptions.html#structfield.format).
+///
+/// ```
+/// use vaspchg_rs::{ChgType, NumberFormat};
+///
+/// // CHGCAR layout with 6 values per row
+/// let format = NumberFormat { per_row: 6, ..NumberFormat::default_for(ChgType::Chgcar) };
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NumberFormat {
+    /// Significant digits of each value.
+    pub digits:         usize,
+    /// Values per row.
+    pub per_row:        usize,
+    /// Width of each field, not counting the space in front of it. Values are right aligned,
+    /// a `FloatStyle::Fortran` field too narrow for its value is filled with `*` like Fortran does.
+    pub width:          usize,
+    pub style:          FloatStyle,
+}
+
+impl NumberFormat {
+    /// What VASP writes for `chgtype`: `E17.11`, 5 per row for CHGCAR and PARCHG, `E11.5`,
+    /// 10 per row for CHG.
+    pub fn default_for(chgtype: ChgType) -> Self {
+        match chgtype {
+            ChgType::Chg => Self { digits: 5, per_row: 10, width: 11, style: FloatStyle::Fortran },
+            ChgType::Chgcar | ChgType::Parchg =>
+                Self { digits: 11, per_row: 5, width: 17, style: FloatStyle::Fortran },
+        }
+    }
+
+    /// Error of kind `InvalidInput` if nothing can be written with this format.
+    pub(crate) fn check(&self) -> io::Result<()> {
+        if self.digits == 0 || self.per_row == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "digits and values per row must be positive"));
+        }
+        Ok(())
+    }
+
+    /// Append ` v` formatted as one field of a grid row to `out`.
+    pub(crate) fn write_value(&self, out: &mut String, v: f64) {
+        out.push(' ');
+        match self.style {
+            FloatStyle::Fortran => fortran::write_real(out, v, self.digits, self.width),
+            FloatStyle::Rust => {
+                write!(out, "{:>1$.2$E}", v, self.width, self.digits - 1)
+                    .expect("writing to a String cannot fail");
+            },
+        }
+    }
+}
+
+/// Options of [`ChgBase::write_file_with`](struct.ChgBase.html#method.write_file_with) and
+/// [`ChgBase::write_writer_with`](struct.ChgBase.html#method.write_writer_with).
+///
+/// ```
+/// use vaspchg_rs::{WriteOptions, Compression};
+///
+/// let opts = WriteOptions {
+///     compression: Some(Compression::Gzip(9)),
+///     ..Default::default()
+/// };
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct WriteOptions {
+    /// Compress the output on the fly.
+    ///
+    /// `None` means guessing from the file extension in `write_file_with`
+    /// (see [`Compression::from_path`](enum.Compression.html#method.from_path)),
+    /// and plain text in `write_writer_with`.
+    pub compression:    Option<Compression>,
+
+    /// Number formatting of the grids, `None` means what VASP writes for the `ChgType`, see
+    /// [`NumberFormat::default_for`](struct.NumberFormat.html#method.default_for).
+    pub format:         Option<NumberFormat>,
+
+    /// Refuse to replace an existing file in `write_file_with`.
+    pub no_clobber:     bool,
+
+    /// LMMAX of each atom, in the order of the positions, to write zero augmentation
+    /// occupancies for the components that have none when writing a CHGCAR, e.g. one made by
+    /// `ChgBase::from_builder`.
+    ///
+    /// LMMAX depends on the POTCAR of the atom, it is the second number in the
+    /// `augmentation occupancies <atom> <LMMAX>` lines of a CHGCAR that VASP wrote with it.
+    pub zero_aug_lmmax: Option<Vec<usize>>,
+
+    /// Write a summary of the provenance log to the title line of the header, cut to 80
+    /// characters, see [`Provenance::title`](struct.Provenance.html#method.title).
+    pub provenance_title: bool,
+
+    /// Write the whole provenance log to a JSON sidecar `<path>.provenance.json` in
+    /// `write_file_with`, which `ChgBase::from_file` reads back with the file.
+    ///
+    /// Requires the `json` feature, writing with it set is an error of kind `Unsupported`
+    /// otherwise.
+    pub provenance_sidecar: bool,
+}
@@ -0,0 +1,276 @@
+//! Processing history of volumetric data: the files it was read from and what was done to it.
+//!
+//! The history can be written to the title line of the header, which is short and may be cut,
+//! or in full to a JSON sidecar next to the written file, `CHGCAR.provenance.json` for a
+//! `CHGCAR`. The sidecar holds the SHA-256 of the file it belongs to, so that a sidecar left
+//! behind by a file that was replaced since is not picked up.
+
+use std::io;
+#[cfg(feature = "json")]
+use std::io::{Read, Write};
+use std::path::Path;
+#[cfg(feature = "json")]
+use std::path::PathBuf;
+
+#[cfg(feature = "json")]
+use sha2::{Digest, Sha256};
+
+#[cfg(feature = "json")]
+use crate::atomic;
+#[cfg(feature = "json")]
+use crate::error::Result;
+use crate::options::ReadOptions;
+
+/// Characters of the history written to the title line, longer ones are cut with `...`.
+pub(crate) const TITLE_LEN: usize = 80;
+
+/// A file that data was read from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceFile {
+    /// The path as it was given to the reader.
+    pub path:       String,
+    /// SHA-256 of the file as it is on disk, before decompression, in lower case hex.
+    pub sha256:     String,
+}
+
+/// Processing history of a [`ChgBase`](struct.ChgBase.html), see
+/// [`ChgBase::get_mut_provenance`](struct.ChgBase.html#method.get_mut_provenance).
+///
+/// ```
+/// use vaspchg_rs::{Provenance, SourceFile};
+///
+/// let mut log = Provenance::default();
+/// log.add_source(SourceFile { path: "CHGCAR".into(), sha256: "9f86d081884c7d65".into() });
+/// log.record("scale by 0.5");
+/// assert_eq!(log.title(), "scale by 0.5; from CHGCAR@9f86d081");
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Provenance {
+    sources:    Vec<SourceFile>,
+    operations: Vec<String>,
+}
+
+impl Provenance {
+    /// Files the data was read from, oldest first.
+    pub fn get_sources(&self) -> &[SourceFile]  { &self.sources }
+
+    /// Operations applied to the data, oldest first.
+    pub fn get_operations(&self) -> &[String]   { &self.operations }
+
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty() && self.operations.is_empty()
+    }
+
+    /// Add a source file, unless a file with the same hash and path is there already.
+    pub fn add_source(&mut self, source: SourceFile) {
+        if !self.sources.contains(&source) {
+            self.sources.push(source);
+        }
+    }
+
+    /// Record an operation, e.g. `"sum with CHGCAR_b"`.
+    pub fn record(&mut self, operation: impl Into<String>) {
+        self.operations.push(operation.into());
+    }
+
+    /// Append the history of `other`, e.g. of the second operand of a sum.
+    pub fn merge(&mut self, other: &Provenance) {
+        other.sources.iter().for_each(|s| self.add_source(s.clone()));
+        self.operations.extend(other.operations.iter().cloned());
+    }
+
+    /// One line summary for the title line of the header: the operations, then the names of
+    /// the source files with the first 8 digits of their hashes, at most 80 characters.
+    pub fn title(&self) -> String {
+        let sources = self.sources.iter()
+            .map(|s| {
+                let name = Path::new(&s.path).file_name()
+                    .map_or_else(|| s.path.clone(), |n| n.to_string_lossy().into_owned());
+                format!("{}@{}", name, s.sha256.get(.. 8).unwrap_or(&s.sha256))
+            })
+            .collect::<Vec<_>>();
+        let mut parts = self.operations.clone();
+        if !sources.is_empty() {
+            parts.push(format!("from {}", sources.join(" ")));
+        }
+        let title = parts.join("; ").replace(char::is_control, " ");
+        match title.char_indices().nth(TITLE_LEN) {
+            Some(_) => title.chars().take(TITLE_LEN - 3).chain("...".chars()).collect(),
+            None => title,
+        }
+    }
+}
+
+/// Whether a file read from `path` is hashed: to check its sidecar against, or to record it
+/// with `opts.provenance`.
+#[cfg(feature = "json")]
+pub(crate) fn hash_on_read(path: &Path, opts: &ReadOptions) -> bool {
+    opts.provenance || sidecar_path(path).exists()
+}
+
+/// Refuse `opts.provenance` without the `json` feature, which brings the hashing.
+#[cfg(not(feature = "json"))]
+pub(crate) fn check_read_options(opts: &ReadOptions) -> io::Result<()> {
+    match opts.provenance {
+        true => Err(requires_json("ReadOptions::provenance")),
+        false => Ok(()),
+    }
+}
+
+#[cfg(not(feature = "json"))]
+pub(crate) fn requires_json(option: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, format!("{} requires the `json` feature", option))
+}
+
+/// Where the sidecar of `path` is, `CHGCAR.provenance.json` for `CHGCAR`.
+#[cfg(feature = "json")]
+pub(crate) fn sidecar_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(".provenance.json");
+    path.with_file_name(name)
+}
+
+/// Lower case hex of a finished hash.
+#[cfg(feature = "json")]
+pub(crate) fn hex(hasher: Sha256) -> String {
+    hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Reader that hashes everything read through it.
+#[cfg(feature = "json")]
+pub(crate) struct HashReader<'a, R> {
+    pub inner:      R,
+    pub hasher:     &'a mut Sha256,
+}
+
+#[cfg(feature = "json")]
+impl<R: Read> Read for HashReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[.. n]);
+        Ok(n)
+    }
+}
+
+/// Writer that hashes everything written through it.
+#[cfg(feature = "json")]
+pub(crate) struct HashWriter<'a, W> {
+    pub inner:      W,
+    pub hasher:     &'a mut Sha256,
+}
+
+#[cfg(feature = "json")]
+impl<W: Write> Write for HashWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[.. n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> { self.inner.flush() }
+}
+
+/// Write the sidecar of `path`, a file whose SHA-256 is `sha256`.
+#[cfg(feature = "json")]
+pub(crate) fn write_sidecar(path: &Path, provenance: &Provenance, sha256: &str) -> Result<()> {
+    let sources = provenance.sources.iter()
+        .map(|s| serde_json::json!({ "path": s.path, "sha256": s.sha256 }))
+        .collect::<Vec<_>>();
+    let json = serde_json::json!({
+        "file":         path.file_name().map(|n| n.to_string_lossy()),
+        "sha256":       sha256,
+        "sources":      sources,
+        "operations":   provenance.operations,
+    });
+    let text = serde_json::to_string_pretty(&json).map_err(io::Error::from)?;
+    atomic::write_file(&sidecar_path(path), false, |file| Ok(writeln!(file, "{}", text)?))
+}
+
+/// Read the sidecar of `path`, `None` if there is none or it belongs to a file other than the
+/// one whose SHA-256 is `sha256`. A sidecar that cannot be read or parsed is ignored the same
+/// way, it is no reason to fail reading the file.
+#[cfg(feature = "json")]
+pub(crate) fn read_sidecar(path: &Path, sha256: &str) -> Option<Provenance> {
+    let text = std::fs::read_to_string(sidecar_path(path)).ok()?;
+    let json: serde_json::Value = serde_json::from_str(&text).ok()?;
+    if json["sha256"].as_str()? != sha256 {
+        return None;
+    }
+    let string = |v: &serde_json::Value| v.as_str().map(str::to_owned);
+    let sources = json["sources"].as_array()?.iter()
+        .map(|s| Some(SourceFile { path: string(&s["path"])?, sha256: string(&s["sha256"])? }))
+        .collect::<Option<_>>()?;
+    let operations = json["operations"].as_array()?.iter()
+        .map(string)
+        .collect::<Option<_>>()?;
+    Some(Provenance { sources, operations })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(path: &str, sha256: &str) -> SourceFile {
+        SourceFile { path: path.to_owned(), sha256: sha256.to_owned() }
+    }
+
+    #[test]
+    fn test_title() {
+        let mut log = Provenance::default();
+        assert!(log.is_empty());
+        assert_eq!(log.title(), "");
+        log.add_source(source("run/CHGCAR", "0123456789abcdef"));
+        log.add_source(source("run/CHGCAR", "0123456789abcdef"));
+        assert_eq!(log.get_sources().len(), 1);
+        log.record("scale\nby 2");
+        assert_eq!(log.title(), "scale by 2; from CHGCAR@01234567");
+
+        let mut other = Provenance::default();
+        other.add_source(source("CHGCAR_b", "ff"));
+        other.record("x".repeat(100));
+        log.merge(&other);
+        assert_eq!(log.get_sources().len(), 2);
+        assert_eq!(log.get_operations().len(), 2);
+        let title = log.title();
+        assert_eq!(title.chars().count(), TITLE_LEN);
+        assert!(title.starts_with("scale by 2; xxx") && title.ends_with("x..."));
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_hash() {
+        let mut hasher = Sha256::new();
+        let mut reader = HashReader { inner: &b"abc"[..], hasher: &mut hasher };
+        io::copy(&mut reader, &mut io::sink()).unwrap();
+        assert_eq!(hex(hasher), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_sidecar() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("vaspchg_provenance_{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("CHGCAR");
+        assert_eq!(sidecar_path(&path), dir.join("CHGCAR.provenance.json"));
+        assert_eq!(read_sidecar(&path, "00"), None);
+
+        let mut hasher = Sha256::new();
+        HashWriter { inner: io::sink(), hasher: &mut hasher }.write_all(b"abc")?;
+        assert_eq!(hex(hasher), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+
+        let mut log = Provenance::default();
+        log.add_source(source("CHGCAR_a", "aa"));
+        log.record("sum with \"CHGCAR_b\"");
+        write_sidecar(&path, &log, "00")?;
+        assert_eq!(read_sidecar(&path, "00"), Some(log));
+        assert_eq!(read_sidecar(&path, "01"), None);
+
+        // a corrupt sidecar is ignored like a stale one
+        for corrupt in &["{\"sha256\": \"00\"}", "{1: 2}", "\u{0}"] {
+            std::fs::write(sidecar_path(&path), corrupt)?;
+            assert_eq!(read_sidecar(&path, "00"), None);
+        }
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,184 @@
+use std::io::{self, BufRead};
+
+use crate::error::{Location, Section};
+use crate::meta::SectionStart;
+
+/// Something lines can be pulled from, one at a time.
+pub(crate) trait LineSource {
+    /// Move to the next line, returns `false` on EOF.
+    fn next_line(&mut self) -> io::Result<bool>;
+    /// The current line, including its line ending. Empty after EOF is reached.
+    fn line(&self) -> &str;
+}
+
+/// Lines of a `BufRead`, copied into a buffer that is reused for every line.
+pub(crate) struct Buffered<R> {
+    inner:      R,
+    buf:        String,
+}
+
+impl<R: BufRead> LineSource for Buffered<R> {
+    fn next_line(&mut self) -> io::Result<bool> {
+        self.buf.clear();
+        Ok(self.inner.read_line(&mut self.buf)? > 0)
+    }
+
+    fn line(&self) -> &str { &self.buf }
+}
+
+/// Lines borrowed from a byte slice, e.g. a memory-mapped file, without copying them.
+pub(crate) struct Borrowed<'a> {
+    rest:       &'a [u8],
+    line:       &'a str,
+}
+
+impl<'a> LineSource for Borrowed<'a> {
+    fn next_line(&mut self) -> io::Result<bool> {
+        let len = self.rest.iter().position(|&c| c == b'\n').map_or(self.rest.len(), |i| i + 1);
+        let (line, rest) = self.rest.split_at(len);
+        self.line = std::str::from_utf8(line)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "stream did not contain valid UTF-8"))?;
+        self.rest = rest;
+        Ok(len > 0)
+    }
+
+    fn line(&self) -> &str { self.line }
+}
+
+/// Line reader with one line of lookahead that keeps track of where it is in the stream, so
+/// that parse errors can point to the exact line and byte of the offending input.
+///
+/// Only `BufRead` is required, a line can be pushed back with `unread` instead of seeking.
+pub(crate) struct LineReader<S> {
+    src:        S,      // holds the current line, including line ending
+    pending:    bool,   // the current line was pushed back and will be returned again by `advance`
+    line:       usize,  // number of lines consumed so far
+    offset:     u64,    // number of bytes consumed so far
+    recorded:   Option<String>, // every line read so far, if recording
+}
+
+impl<R: BufRead> LineReader<Buffered<R>> {
+    pub fn new(inner: R) -> Self {
+        Self::starting_at(inner, 0, 1)
+    }
+
+    /// Reader of a stream that was already moved to byte `offset`, at the beginning of `line`.
+    pub fn starting_at(inner: R, offset: u64, line: usize) -> Self {
+        Self::with_source(Buffered { inner, buf: String::new() }, offset, line)
+    }
+}
+
+impl<'a> LineReader<Borrowed<'a>> {
+    /// Reader of lines borrowed from `bytes`.
+    pub fn from_slice(bytes: &'a [u8]) -> Self {
+        Self::with_source(Borrowed { rest: bytes, line: "" }, 0, 1)
+    }
+}
+
+impl<S: LineSource> LineReader<S> {
+    fn with_source(src: S, offset: u64, line: usize) -> Self {
+        Self { src, pending: false, line: line - 1, offset, recorded: None }
+    }
+
+    /// Keep a copy of every line read from now on, see `take_recorded`.
+    pub fn record(&mut self) {
+        self.recorded = Some(String::new());
+    }
+
+    /// The text read since `record` was called, including a line pushed back by `unread`.
+    pub fn take_recorded(&mut self) -> Option<String> {
+        self.recorded.take()
+    }
+
+    /// Move to the next line, returns `false` on EOF.
+    pub fn advance(&mut self) -> io::Result<bool> {
+        if !self.pending {
+            if !self.src.next_line()? {
+                return Ok(false);
+            }
+            if let Some(text) = self.recorded.as_mut() {
+                text.push_str(self.src.line());
+            }
+        }
+        self.pending = false;
+        self.line += 1;
+        self.offset += self.src.line().len() as u64;
+        Ok(true)
+    }
+
+    /// The current line, including its line ending. Empty after EOF is reached.
+    pub fn current(&self) -> &str { self.src.line() }
+
+    /// Push the current line back, so that the next `advance` returns it again.
+    pub fn unread(&mut self) {
+        if !self.pending && !self.current().is_empty() {
+            self.pending = true;
+            self.line -= 1;
+            self.offset -= self.current().len() as u64;
+        }
+    }
+
+    /// Number of bytes consumed, a line pushed back by `unread` is not counted.
+    pub fn offset(&self) -> u64 { self.offset }
+
+    /// Byte offset where the current line begins.
+    pub fn line_start(&self) -> u64 {
+        if self.pending {
+            self.offset
+        } else {
+            self.offset - self.current().len() as u64
+        }
+    }
+
+    /// Where the current line begins.
+    pub fn section_start(&self) -> SectionStart {
+        SectionStart { offset: self.line_start(), line: self.line }
+    }
+
+    /// Location of the byte `col` in the current line, line 1 if EOF is hit before any line.
+    pub fn location(&self, section: Section, col: usize) -> Location {
+        Location { section, line: self.line.max(1), offset: self.line_start() + col as u64 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "first\r\n\n  third";
+
+    fn lines<S: LineSource>(mut file: LineReader<S>) -> Vec<(String, u64, usize)> {
+        let mut out = vec![];
+        while file.advance().unwrap() {
+            out.push((file.current().to_owned(), file.line_start(), file.line));
+            if file.line == 2 && out.len() == 2 {
+                file.unread();
+            }
+        }
+        assert_eq!(file.current(), "");
+        out
+    }
+
+    #[test]
+    fn test_sources() {
+        let expected = vec![("first\r\n".to_owned(), 0, 1), ("\n".to_owned(), 7, 2),
+                            ("\n".to_owned(), 7, 2), ("  third".to_owned(), 8, 3)];
+        assert_eq!(lines(LineReader::new(TEXT.as_bytes())), expected);
+        assert_eq!(lines(LineReader::from_slice(TEXT.as_bytes())), expected);
+        assert!(LineReader::from_slice(b"\xff\n").advance().is_err());
+    }
+
+    #[test]
+    fn test_record() {
+        let mut file = LineReader::new(TEXT.as_bytes());
+        file.advance().unwrap();
+        file.record();
+        file.advance().unwrap();
+        file.unread();
+        assert_eq!(file.offset(), 7);
+        while file.advance().unwrap() {}
+        assert_eq!(file.offset(), TEXT.len() as u64);
+        assert_eq!(file.take_recorded().as_deref(), Some(&TEXT[7 ..]));
+        assert_eq!(file.take_recorded(), None);
+    }
+}
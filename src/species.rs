@@ -0,0 +1,110 @@
+//! Species names for headers without the element symbol line, as written by VASP 4.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+use crate::compress;
+use crate::error::Result;
+use crate::options::{ReadOptions, SpeciesSource};
+
+/// Resolve the species names requested in `opts`, `path` is the file being read, if any.
+pub(crate) fn resolve(opts: &ReadOptions, path: Option<&Path>) -> Result<Option<Vec<String>>> {
+    Ok(match &opts.species {
+        None => None,
+        Some(SpeciesSource::Names(names)) => Some(names.clone()),
+        Some(SpeciesSource::Potcar(potcar)) => Some(read_potcar(potcar)?),
+        Some(SpeciesSource::AdjacentPotcar) => {
+            let dir = path.and_then(|p| p.parent())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound,
+                                              "no file path to look for an adjacent POTCAR"))?;
+            Some(read_potcar(&dir.join("POTCAR"))?)
+        },
+    })
+}
+
+/// Element symbols of a POTCAR, in the order of the potentials, compressed POTCARs are fine.
+pub(crate) fn read_potcar(path: &Path) -> Result<Vec<String>> {
+    let file = compress::decompress(BufReader::new(File::open(path)?))?;
+    let names = parse_potcar(file)?;
+    if names.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("no TITEL or VRHFIN line in {}", path.display())).into());
+    }
+    Ok(names)
+}
+
+/// Take the symbols from the `TITEL` lines, e.g. `TITEL  = PAW_PBE Fe_pv 06Sep2000` gives `Fe`,
+/// or from the `VRHFIN` lines, e.g. `VRHFIN =Fe: 3p4s3d`, if there are no `TITEL` lines.
+fn parse_potcar(file: impl BufRead) -> io::Result<Vec<String>> {
+    let mut titel = vec![];
+    let mut vrhfin = vec![];
+    for line in file.lines() {
+        let line = line?;
+        let mut kv = line.splitn(2, '=');
+        let (key, value) = match (kv.next(), kv.next()) {
+            (Some(key), Some(value)) => (key.trim(), value.trim()),
+            _ => continue,
+        };
+        if key == "TITEL" {
+            if let Some(symbol) = value.split_ascii_whitespace().nth(1) {
+                titel.push(element(symbol));
+            }
+        } else if key == "VRHFIN" {
+            if let Some(symbol) = value.split(':').next().filter(|s| !s.trim().is_empty()) {
+                vrhfin.push(element(symbol.trim()));
+            }
+        }
+    }
+    Ok(if titel.is_empty() { vrhfin } else { titel })
+}
+
+/// Strip the variant of a potential, `Fe_pv` or `H.75` give `Fe` and `H`.
+fn element(symbol: &str) -> String {
+    symbol.split(&['_', '.'][..]).next().unwrap_or(symbol).to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_potcar() {
+        let potcar = "  PAW_PBE Fe_pv 06Sep2000
+   VRHFIN =Fe: 3p4s3d
+   TITEL  = PAW_PBE Fe_pv 06Sep2000
+   POMASS =   55.847; ZVAL   =   14.000    mass and valenz
+ End of Dataset
+  PAW_PBE O 08Apr2002
+   VRHFIN =O: s2p4
+   TITEL  = PAW_PBE O 08Apr2002
+ End of Dataset
+  PAW_PBE H.75 07Oct2005
+   TITEL  = PAW_PBE H.75 07Oct2005
+ End of Dataset
+";
+        assert_eq!(parse_potcar(potcar.as_bytes()).unwrap(), vec!["Fe", "O", "H"]);
+
+        let vrhfin_only = "   VRHFIN =Li_sv: 1s2s2p\n   VRHFIN =Mn: 3d4s\n";
+        assert_eq!(parse_potcar(vrhfin_only.as_bytes()).unwrap(), vec!["Li", "Mn"]);
+        assert!(parse_potcar("nothing here\n".as_bytes()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_resolve() {
+        let opts = ReadOptions {
+            species: Some(SpeciesSource::Names(vec!["Li".to_owned()])),
+            ..Default::default()
+        };
+        assert_eq!(resolve(&opts, None).unwrap(), Some(vec!["Li".to_owned()]));
+        assert_eq!(resolve(&ReadOptions::default(), None).unwrap(), None);
+
+        let opts = ReadOptions { species: Some(SpeciesSource::AdjacentPotcar), ..Default::default() };
+        assert!(resolve(&opts, None).is_err());
+        let opts = ReadOptions {
+            species: Some(SpeciesSource::Potcar("no/such/POTCAR".into())),
+            ..Default::default()
+        };
+        assert!(matches!(resolve(&opts, None), Err(crate::error::ChgError::Io(_))));
+    }
+}
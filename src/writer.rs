@@ -0,0 +1,298 @@
+use std::io::{self, BufWriter, Write};
+
+use ndarray::ArrayView2;
+use vasp_poscar::Poscar;
+
+use crate::base::{ChgBase, ChgType};
+use crate::compress::{Compression, Encoder};
+use crate::error::{ChgError, Result};
+use crate::float::ChgFloat;
+use crate::meta::Component;
+use crate::options::{NumberFormat, WriteOptions};
+
+/// Volumetric data written slab by slab, without ever holding a whole grid.
+///
+/// The header and the `NGX NGY NGZ` line of the total density are written on construction,
+/// then the grid is written one z-slab of shape `[NGX, NGY]` at a time, bottom to top. A
+/// CHGCAR needs the augmentation occupancies of each grid after its last slab, then
+/// `next_component` starts the next grid, e.g. the magnetization of a spin-polarized file,
+/// which VASP writes after the magnetic moments of the ions, see `write_magmom`.
+/// The text is identical to what `ChgBase::write_writer_with` writes for the same data.
+///
+/// ```no_run
+/// use ndarray::Array2;
+/// use vaspchg_rs::{ChgBase, ChgType, ChgWriter};
+///
+/// let pos = ChgBase::scan_metadata("CHGCAR").unwrap().get_poscar().clone();
+/// let file = std::fs::File::create("PARCHG").unwrap();
+/// let mut writer = ChgWriter::new(file, &pos, [40, 40, 60], ChgType::Parchg).unwrap();
+/// for z in 0 .. 60 {
+///     let slab = Array2::<f64>::from_elem((40, 40), z as f64);
+///     writer.write_slab(slab.view()).unwrap();
+/// }
+/// writer.finish().unwrap();
+/// ```
+///
+/// `finish` has to be called to complete the file, a writer that is dropped leaves the last
+/// grid and the compression trailer unwritten.
+pub struct ChgWriter<W: Write> {
+    file:       BufWriter<Encoder<W>>,
+    chgtype:    ChgType,
+    format:     NumberFormat,
+    ngrid:      [usize; 3],
+    volume:     f64,
+    nsites:     usize,
+    lmmax:      Option<Vec<usize>>,
+    buf:        String,
+
+    // Position in the file
+    component:  usize,  // grids started so far minus one, 0 is the total density
+    nslabs:     usize,  // slabs of the current grid written
+    col:        usize,  // values in the last row written, if it is not complete
+    has_aug:    bool,   // augmentation occupancies of the current grid are written
+    has_magmom: bool,   // magnetic moments after the current grid are written
+}
+
+impl<W: Write> ChgWriter<W> {
+    pub fn new(file: W, pos: &Poscar, ngrid: [usize; 3], chgtype: ChgType) -> Result<Self> {
+        Self::with_options(file, pos, ngrid, chgtype, &WriteOptions::default())
+    }
+
+    /// Start writing a file of `chgtype` with `ngrid` points, the total density is multiplied
+    /// by the volume of `pos` as it is written.
+    ///
+    /// `opts.compression` and `opts.format` are used as in `ChgBase::write_writer_with`, and
+    /// zero occupancies are written for the grids without any if `opts.zero_aug_lmmax` is given.
+    /// `opts.no_clobber` has no effect, `file` is already open.
+    pub fn with_options(file: W, pos: &Poscar, ngrid: [usize; 3], chgtype: ChgType,
+                        opts: &WriteOptions) -> Result<Self> {
+        let format = opts.format.unwrap_or_else(|| NumberFormat::default_for(chgtype));
+        format.check()?;
+        let compression = opts.compression.unwrap_or(Compression::Plain);
+        let mut file = BufWriter::new(Encoder::new(file, compression)?);
+        ChgBase::_write_header(&mut file, pos, None, None)?;
+        ChgBase::_write_ngrid(&mut file, &ngrid)?;
+        Ok(Self {
+            file,
+            chgtype,
+            format,
+            ngrid,
+            volume: pos.scaled_volume(),
+            nsites: pos.num_sites(),
+            lmmax: opts.zero_aug_lmmax.clone(),
+            buf: String::new(),
+            component: 0,
+            nslabs: 0,
+            col: 0,
+            has_aug: false,
+            has_magmom: false,
+        })
+    }
+
+    /// The component whose grid or occupancies are written now.
+    pub fn get_component(&self) -> Component { Component::at(self.component) }
+
+    /// Number of z-slabs of the current grid written so far.
+    pub fn get_nslabs(&self) -> usize       { self.nslabs }
+
+    /// Write the next z-slab of the current grid, `slab[[x, y]]` is the value at `(x, y)`.
+    pub fn write_slab<T: ChgFloat>(&mut self, slab: ArrayView2<'_, T>) -> Result<()> {
+        if slab.shape() != &self.ngrid[.. 2] {
+            return Err(invalid_input(format!("slab of shape {:?} in a grid of {:?}",
+                                             slab.shape(), self.ngrid)));
+        }
+        if self.nslabs == self.ngrid[2] {
+            return Err(invalid_input(format!("all {} slabs of {:?} are written already",
+                                             self.nslabs, self.get_component())));
+        }
+        let volume = if self.component == 0 { self.volume } else { 1.0 };
+        self.buf.clear();
+        // x is the fastest index, which is the logical order of the transposed view
+        for v in slab.t().iter() {
+            self.format.write_value(&mut self.buf, v.to_f64() * volume);
+            self.col += 1;
+            if self.col == self.format.per_row {
+                self.buf.push('\n');
+                self.col = 0;
+            }
+        }
+        self.nslabs += 1;
+        if self.nslabs == self.ngrid[2] && self.col > 0 {
+            self.buf.push('\n');
+            self.col = 0;
+        }
+        Ok(self.file.write_all(self.buf.as_bytes())?)
+    }
+
+    /// Write the augmentation occupancies of the current grid, after its last slab. Only a
+    /// CHGCAR has them, blank text is `ChgError::MissingAugmentation`.
+    pub fn write_aug(&mut self, aug: &str) -> Result<()> {
+        if self.chgtype != ChgType::Chgcar {
+            return Err(invalid_input(format!("no augmentation occupancies in {:?}", self.chgtype)));
+        }
+        self._check_grid_complete()?;
+        if aug.trim().is_empty() {
+            return Err(ChgError::MissingAugmentation(self.get_component()));
+        }
+        if self.has_aug {
+            return Err(invalid_input(format!("augmentation occupancies of {:?} are written already",
+                                             self.get_component())));
+        }
+        // the same blank line after the occupancies of a diff component as `write_writer`
+        match self.component {
+            0 => write!(self.file, "{}", aug)?,
+            _ => writeln!(self.file, "{}", aug)?,
+        }
+        self.has_aug = true;
+        Ok(())
+    }
+
+    /// Write the per-ion magnetic moments after the current grid and its augmentation
+    /// occupancies, as raw text like `ChgBase::get_magmom`.
+    pub fn write_magmom(&mut self, magmom: &str) -> Result<()> {
+        self._end_component()?;
+        if self.has_magmom {
+            return Err(invalid_input(format!("magnetic moments after {:?} are written already",
+                                             self.get_component())));
+        }
+        self.file.write_all(magmom.as_bytes())?;
+        self.has_magmom = true;
+        Ok(())
+    }
+
+    /// Complete the current grid and write the `NGX NGY NGZ` line of the next one.
+    pub fn next_component(&mut self) -> Result<()> {
+        self._end_component()?;
+        ChgBase::_write_ngrid(&mut self.file, &self.ngrid)?;
+        self.component += 1;
+        self.nslabs = 0;
+        self.has_aug = false;
+        self.has_magmom = false;
+        Ok(())
+    }
+
+    /// Complete the current grid and flush the output, returns the inner writer.
+    pub fn finish(mut self) -> Result<W> {
+        self._end_component()?;
+        Ok(self.file.into_inner().map_err(|e| e.into_error())?.finish()?)
+    }
+
+    /// Check that the current grid is complete and write zero occupancies if they are missing.
+    fn _end_component(&mut self) -> Result<()> {
+        self._check_grid_complete()?;
+        if self.chgtype != ChgType::Chgcar || self.has_aug {
+            return Ok(());
+        }
+        match self.lmmax.as_deref() {
+            Some(lmmax) => {
+                let aug = ChgBase::_zero_aug(lmmax, self.nsites)?;
+                self.write_aug(&aug)
+            },
+            None => Err(ChgError::MissingAugmentation(self.get_component())),
+        }
+    }
+
+    fn _check_grid_complete(&self) -> Result<()> {
+        if self.nslabs < self.ngrid[2] {
+            return Err(invalid_input(format!("{} of {} slabs of {:?} are written",
+                                             self.nslabs, self.ngrid[2], self.get_component())));
+        }
+        Ok(())
+    }
+}
+
+fn invalid_input(msg: String) -> ChgError {
+    io::Error::new(io::ErrorKind::InvalidInput, msg).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::{Array3, Axis};
+
+    const POSCAR: &str = "\
+unknown system
+1.0
+2.969072   -0.000523   -0.000907
+-0.987305    2.800110    0.000907
+-0.987305   -1.402326    2.423654
+Li
+1
+Direct
+0.000000  0.000000  0.000000
+";
+
+    fn write_slabs<W: Write>(writer: &mut ChgWriter<W>, chg: &Array3<f64>) -> Result<()> {
+        chg.axis_iter(Axis(2)).try_for_each(|slab| writer.write_slab(slab))
+    }
+
+    #[test]
+    fn test_chg_writer() -> Result<()> {
+        let pos = Poscar::from_reader(POSCAR.as_bytes())?;
+        let chg = Array3::from_shape_fn((2, 3, 4), |(i, j, k)| (i + 2 * j + 6 * k) as f64 * 0.01 - 0.1);
+        let mag = chg.mapv(|v| v * 0.5);
+        let aug = "augmentation occupancies   1   2\n  0.1000000E+00  0.2000000E+00\n";
+        let mut expected = ChgBase::from_builder(chg.clone(), vec![mag.clone()], pos.clone());
+
+        // 6 values per slab, rows of 5 or 10 values go across slabs
+        for chgtype in [ChgType::Chg, ChgType::Parchg] {
+            let mut writer = ChgWriter::new(vec![], &pos, [2, 3, 4], chgtype)?;
+            write_slabs(&mut writer, &chg)?;
+            writer.next_component()?;
+            assert_eq!(writer.get_component(), Component::Diff(0));
+            write_slabs(&mut writer, &mag)?;
+            let mut out = vec![];
+            expected.write_writer(&mut out, chgtype)?;
+            assert_eq!(String::from_utf8(writer.finish()?).unwrap(), String::from_utf8(out).unwrap());
+        }
+
+        let opts = WriteOptions { zero_aug_lmmax: Some(vec![3]), ..Default::default() };
+        let mut writer = ChgWriter::with_options(vec![], &pos, [2, 3, 4], ChgType::Chgcar, &opts)?;
+        write_slabs(&mut writer, &chg)?;
+        writer.write_aug(aug)?;
+        writer.write_magmom("  0.6\n")?;
+        assert!(writer.write_magmom("  0.6\n").is_err());
+        assert!(writer.write_aug(aug).is_err());
+        writer.next_component()?;
+        write_slabs(&mut writer, &mag)?;
+        let written = writer.finish()?;
+        let written = ChgBase::from_bytes(&written)?;
+        assert_eq!(written.get_total_aug().map(String::as_str), Some(aug));
+        assert!(written.get_diff_aug()[0].starts_with("augmentation occupancies   1   3\n"));
+        assert_eq!(written.get_magmom()[0], "  0.6\n");
+
+        *expected.get_mut_diff_chg() = vec![];
+        let mut out = vec![];
+        expected.write_writer_with(&mut out, ChgType::Chgcar, &opts)?;
+        let mut writer = ChgWriter::with_options(vec![], &pos, [2, 3, 4], ChgType::Chgcar, &opts)?;
+        write_slabs(&mut writer, &chg)?;
+        assert_eq!(writer.finish()?, out);
+        Ok(())
+    }
+
+    #[test]
+    fn test_chg_writer_errors() -> Result<()> {
+        let pos = Poscar::from_reader(POSCAR.as_bytes())?;
+        let slab = ndarray::Array2::<f32>::zeros((2, 3));
+        let aug = "augmentation occupancies   1   1\n  0.1000000E+00\n";
+        let mut writer = ChgWriter::new(vec![], &pos, [2, 3, 1], ChgType::Chgcar)?;
+        assert!(writer.write_slab(slab.t()).is_err());
+        assert!(writer.write_aug(aug).is_err());
+        assert!(writer.next_component().is_err());
+        writer.write_slab(slab.view())?;
+        assert_eq!(writer.get_nslabs(), 1);
+        assert!(writer.write_slab(slab.view()).is_err());
+        assert!(matches!(writer.next_component(), Err(ChgError::MissingAugmentation(Component::Total))));
+        assert!(matches!(writer.write_aug(" \n"), Err(ChgError::MissingAugmentation(Component::Total))));
+        writer.write_aug(aug)?;
+        assert!(writer.write_aug(aug).is_err());
+        writer.next_component()?;
+        assert!(writer.finish().is_err());
+
+        let mut writer = ChgWriter::new(vec![], &pos, [2, 3, 1], ChgType::Chg)?;
+        writer.write_slab(slab.view())?;
+        assert!(writer.write_aug(aug).is_err());
+        writer.finish()?;
+        Ok(())
+    }
+}
@@ -1,14 +1,12 @@
 use std::io;
-use std::io::Read;
 use std::path::{PathBuf};
-use std::fs::File;
 use std::fs::remove_file;
 
-use flate2::read::GzDecoder;
-
 use vaspchg_rs::{
     ChgBase,
+    ChgError,
     ChgType,
+    Component,
 };
 
 use crate::get_fpath_in_curr_dir;
@@ -16,14 +14,7 @@ use crate::get_fpath_in_curr_dir;
 #[test]
 fn test_read() -> io::Result<()> {
     let path = get_fpath_in_curr_dir!("CHGCAR.Fe3O4.gz");
-    let file = File::open(path)?;
-    let mut gz = GzDecoder::new(file);
-    let mut s = String::new();
-
-    gz.read_to_string(&mut s)?;
-    let mut stream = io::Cursor::new(s.as_bytes());
-
-    let chg = ChgBase::from_reader(&mut stream)?;
+    let chg = ChgBase::from_file(&path)?;
     let mut stream = io::Cursor::new(vec![0u8; 0]);
     chg.write_writer(&mut stream, ChgType::Chgcar)?;
     assert_eq!(149805, String::from_utf8(stream.get_ref().clone()).unwrap().lines().count());
@@ -35,18 +26,16 @@ fn test_read() -> io::Result<()> {
 #[test]
 fn test_read_ref() -> io::Result<()> {
     let path = get_fpath_in_curr_dir!("CHGCAR.Fe3O4_ref.gz");
-    let file = File::open(path)?;
-    let mut gz = GzDecoder::new(file);
-    let mut s = String::new();
-
-    gz.read_to_string(&mut s)?;
-    let mut stream = io::Cursor::new(s.as_bytes());
-
-    let chg = ChgBase::from_reader(&mut stream)?;
+    let chg = ChgBase::from_file(&path)?;
+    assert_eq!(chg.get_chgtype(), ChgType::Parchg);
+    assert_eq!(chg.get_total_aug(), None);
     let mut stream = io::Cursor::new(vec![0u8; 0]);
-    chg.write_writer(&mut stream, ChgType::Chgcar)?;
+    // the file has no augmentation occupancies to write a CHGCAR with
+    assert!(matches!(chg.write_writer(&mut stream, ChgType::Chgcar),
+                     Err(ChgError::MissingAugmentation(Component::Total))));
+    chg.write_writer(&mut stream, ChgType::Parchg)?;
     assert_eq!(74674, String::from_utf8(stream.get_ref().clone()).unwrap().lines().count());
-    chg.write_file(&get_fpath_in_curr_dir!("CHGCAR_ref_test.vasp"), ChgType::Chgcar)?;
+    chg.write_file(&get_fpath_in_curr_dir!("CHGCAR_ref_test.vasp"), ChgType::Parchg)?;
     remove_file(&get_fpath_in_curr_dir!("CHGCAR_ref_test.vasp"))?;
     Ok(())
 }
\ No newline at end of file
@@ -1,29 +1,27 @@
-use std::io;
-use std::io::Read;
+use std::io::{self, Read};
 use std::path::{PathBuf};
-use std::fs::File;
-use std::fs::remove_file;
+use std::fs::{create_dir_all, metadata, remove_dir_all, remove_file, write, File};
 
 use flate2::read::GzDecoder;
-
+#[cfg(feature = "json")]
+use sha2::{Digest, Sha256};
 use vaspchg_rs::{
     ChgBase,
     ChgType,
+    Compression,
+    ReadOptions,
+    SpeciesSource,
+    WriteOptions,
 };
+#[cfg(feature = "json")]
+use vaspchg_rs::SourceFile;
 
 use crate::get_fpath_in_curr_dir;
 
 #[test]
 fn test_read() -> io::Result<()> {
     let path = get_fpath_in_curr_dir!("CHGCAR.nospin.gz");
-    let file = File::open(path)?;
-    let mut gz = GzDecoder::new(file);
-    let mut s = String::new();
-
-    gz.read_to_string(&mut s)?;
-    let mut stream = io::Cursor::new(s.as_bytes());
-
-    let chg = ChgBase::from_reader(&mut stream)?;
+    let chg = ChgBase::from_file(&path)?;
     let mut stream = io::Cursor::new(vec![0u8; 0]);
     chg.write_writer(&mut stream, ChgType::Chgcar)?;
     assert_eq!(6569, String::from_utf8(stream.get_ref().clone()).unwrap().lines().count());
@@ -31,3 +29,153 @@ fn test_read() -> io::Result<()> {
     remove_file(&get_fpath_in_curr_dir!("CHGCAR_no_spin.vasp"))?;
     Ok(())
 }
+
+#[test]
+fn test_write_compressed() -> io::Result<()> {
+    let chg = ChgBase::from_file(&get_fpath_in_curr_dir!("CHGCAR.nospin.gz"))?;
+    let plain = get_fpath_in_curr_dir!("CHGCAR_no_spin_plain.vasp");
+    let gzipped = get_fpath_in_curr_dir!("CHGCAR_no_spin_compressed.vasp.gz");
+    chg.write_file(&plain, ChgType::Chgcar)?;
+    chg.write_file(&gzipped, ChgType::Chgcar)?;
+
+    let plain_size = metadata(&plain)?.len();
+    assert!(metadata(&gzipped)?.len() * 2 < plain_size);
+
+    let chg_plain = ChgBase::from_file(&plain)?;
+    let chg_gzipped = ChgBase::from_file(&gzipped)?;
+    assert_eq!(chg_plain.get_total_chg(), chg_gzipped.get_total_chg());
+    assert_eq!(chg_plain.get_total_aug(), chg_gzipped.get_total_aug());
+
+    let opts = WriteOptions { compression: Some(Compression::Gzip(1)), ..Default::default() };
+    let mut stream = io::Cursor::new(vec![0u8; 0]);
+    chg.write_writer_with(&mut stream, ChgType::Chgcar, &opts)?;
+    assert_eq!(&stream.get_ref()[.. 2], &[0x1f, 0x8b]);
+
+    let opts = WriteOptions { no_clobber: true, ..Default::default() };
+    assert!(chg.write_file_with(&plain, ChgType::Parchg, &opts).is_err());
+    assert_eq!(metadata(&plain)?.len(), plain_size);
+
+    remove_file(&plain)?;
+    remove_file(&gzipped)?;
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "mmap")]
+fn test_read_mmap() -> io::Result<()> {
+    let path = get_fpath_in_curr_dir!("CHGCAR.nospin.gz");
+    let chg = ChgBase::from_file(&path)?;
+    let plain = get_fpath_in_curr_dir!("CHGCAR_no_spin_mmap.vasp");
+    chg.write_file(&plain, ChgType::Chgcar)?;
+
+    let expected = ChgBase::from_file(&plain)?;
+    let mapped = ChgBase::from_file_mmap(&plain)?;
+    assert_eq!(mapped.get_total_chg(), expected.get_total_chg());
+    assert_eq!(mapped.get_total_aug(), expected.get_total_aug());
+    assert_eq!(mapped.get_ngrid(), expected.get_ngrid());
+
+    // compressed files are not mapped, but still read
+    assert_eq!(ChgBase::from_file_mmap(&path)?.get_total_chg(), chg.get_total_chg());
+
+    remove_file(&plain)?;
+    Ok(())
+}
+
+#[test]
+fn test_read_f32() -> io::Result<()> {
+    let path = get_fpath_in_curr_dir!("CHGCAR.nospin.gz");
+    let chg = ChgBase::from_file(&path)?;
+    let chg32 = ChgBase::<f32>::from_file_as(&path, &ReadOptions::default())?;
+    assert_eq!(chg32.get_total_chg(), chg.convert::<f32>().get_total_chg());
+    assert_eq!(chg32.get_total_aug(), chg.get_total_aug());
+
+    let mut stream = io::Cursor::new(vec![0u8; 0]);
+    chg32.write_writer(&mut stream, ChgType::Chgcar)?;
+    assert_eq!(6569, String::from_utf8(stream.get_ref().clone()).unwrap().lines().count());
+    Ok(())
+}
+
+#[test]
+fn test_species_from_potcar() -> io::Result<()> {
+    let dir = get_fpath_in_curr_dir!("vasp4");
+    create_dir_all(&dir)?;
+    let chg = ChgBase::from_file(&get_fpath_in_curr_dir!("CHGCAR.nospin.gz"))?;
+    let mut text = vec![];
+    chg.write_writer(&mut text, ChgType::Chgcar)?;
+    let text = String::from_utf8(text).unwrap();
+    let vasp4: Vec<&str> = text.lines().enumerate().filter(|&(i, _)| i != 5).map(|(_, l)| l).collect();
+    write(dir.join("CHGCAR"), vasp4.join("\n"))?;
+    write(dir.join("POTCAR"), "  PAW_PBE Li_sv 23Jan2001\n   TITEL  = PAW_PBE Li_sv 23Jan2001\n")?;
+
+    let opts = ReadOptions { species: Some(SpeciesSource::AdjacentPotcar), ..Default::default() };
+    let read = ChgBase::from_file_with(&dir.join("CHGCAR"), &opts)?;
+    let symbols: Vec<&str> = read.get_poscar().group_symbols().unwrap().collect();
+    assert_eq!(symbols, vec!["Li"]);
+    assert_eq!(read.get_total_chg(), chg.get_total_chg());
+    assert!(ChgBase::from_file(&dir.join("CHGCAR"))?.get_poscar().group_symbols().is_none());
+
+    remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_lossless() -> io::Result<()> {
+    let path = get_fpath_in_curr_dir!("CHGCAR.nospin.gz");
+    let mut original = vec![];
+    GzDecoder::new(File::open(&path)?).read_to_end(&mut original)?;
+
+    let opts = ReadOptions { lossless: true, ..Default::default() };
+    let chg = ChgBase::from_file_with(&path, &opts)?;
+    let mut written = vec![];
+    chg.write_writer(&mut written, ChgType::Chgcar)?;
+    assert!(written == original, "lossless output differs from the original");
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "json")]
+fn test_provenance() -> io::Result<()> {
+    let path = get_fpath_in_curr_dir!("CHGCAR.nospin.gz");
+    let sha256 = |path: &PathBuf| -> io::Result<String> {
+        Ok(Sha256::digest(std::fs::read(path)?).iter().map(|b| format!("{:02x}", b)).collect())
+    };
+    let read_opts = ReadOptions { provenance: true, ..Default::default() };
+    let mut chg = ChgBase::from_file_with(&path, &read_opts)?;
+    let source = SourceFile { path: path.display().to_string(), sha256: sha256(&path)? };
+    assert_eq!(chg.get_provenance().get_sources(), std::slice::from_ref(&source));
+
+    chg.get_mut_provenance().record("scale by 2");
+    *chg.get_mut_total_chg() *= 2.0;
+    let out = get_fpath_in_curr_dir!("CHGCAR_no_spin_provenance.vasp");
+    let opts = WriteOptions { provenance_title: true, provenance_sidecar: true, ..Default::default() };
+    chg.write_file_with(&out, ChgType::Chgcar, &opts)?;
+
+    let mut title = String::new();
+    io::BufRead::read_line(&mut io::BufReader::new(File::open(&out)?), &mut title)?;
+    assert_eq!(title.trim_end(), chg.get_provenance().title());
+    assert!(title.starts_with("scale by 2; from CHGCAR.nospin.gz@"));
+
+    // the sidecar is picked up without asking
+    let written = ChgBase::from_file(&out)?;
+    let log = written.get_provenance();
+    assert_eq!(log.get_operations(), &["scale by 2".to_owned()]);
+    assert_eq!(log.get_sources().len(), 2);
+    assert_eq!(log.get_sources()[0], source);
+    assert_eq!(log.get_sources()[1].sha256, sha256(&out)?);
+
+    // a stale sidecar is ignored
+    chg.write_file(&out, ChgType::Chgcar)?;
+    assert!(ChgBase::from_file(&out)?.get_provenance().is_empty());
+    let log = ChgBase::from_file_with(&out, &read_opts)?.get_provenance().clone();
+    assert_eq!(log.get_operations().len(), 0);
+    assert_eq!(log.get_sources()[0].sha256, sha256(&out)?);
+
+    // and so is a corrupt one
+    let sidecar = get_fpath_in_curr_dir!("CHGCAR_no_spin_provenance.vasp.provenance.json");
+    std::fs::write(&sidecar, "{1: 2}")?;
+    assert!(ChgBase::from_file(&out)?.get_provenance().is_empty());
+
+    remove_file(&out)?;
+    remove_file(get_fpath_in_curr_dir!("CHGCAR_no_spin_provenance.vasp.provenance.json"))?;
+    Ok(())
+}
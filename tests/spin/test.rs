@@ -1,14 +1,16 @@
-use std::io;
-use std::io::Read;
-use std::fs::File;
+use std::io::{self, Read};
 use std::path::{PathBuf};
-use std::fs::remove_file;
+use std::fs::{remove_file, File};
 
 use flate2::read::GzDecoder;
-
+use ndarray::Axis;
 use vaspchg_rs::{
     ChgType,
     ChgBase,
+    ChgWriter,
+    Component,
+    LazyChg,
+    ReadOptions,
 };
 
 use crate::get_fpath_in_curr_dir;
@@ -16,14 +18,7 @@ use crate::get_fpath_in_curr_dir;
 #[test]
 fn test_read() -> io::Result<()> {
     let path = get_fpath_in_curr_dir!("CHGCAR.spin.gz");
-    let file = File::open(path)?;
-    let mut gz = GzDecoder::new(file);
-    let mut s = String::new();
-
-    gz.read_to_string(&mut s)?;
-    let mut stream = io::Cursor::new(s.as_bytes());
-
-    let chg = ChgBase::from_reader(&mut stream)?;
+    let chg = ChgBase::from_file(&path)?;
     let mut stream = io::Cursor::new(vec![0u8; 0]);
     chg.write_writer(&mut stream, ChgType::Chgcar)?;
     assert_eq!(44260, String::from_utf8(stream.get_ref().clone()).unwrap().lines().count());
@@ -31,3 +26,169 @@ fn test_read() -> io::Result<()> {
     remove_file(&get_fpath_in_curr_dir!("CHGCAR_out_test.vasp"))?;
     Ok(())
 }
+
+#[test]
+fn test_scan_metadata() -> io::Result<()> {
+    let path = get_fpath_in_curr_dir!("CHGCAR.spin.gz");
+    let meta = ChgBase::scan_metadata(&path)?;
+    let chg = ChgBase::from_file(&path)?;
+    assert_eq!(meta.get_ngrid(), chg.get_ngrid());
+    assert_eq!(meta.get_ncomponents(), 1 + chg.get_diff_chg().len());
+    assert!(meta.is_spin_polarized());
+    assert!(meta.has_aug());
+    assert_eq!(meta.get_poscar().num_sites(), chg.get_poscar().num_sites());
+    Ok(())
+}
+
+#[test]
+fn test_read_component() -> io::Result<()> {
+    let path = get_fpath_in_curr_dir!("CHGCAR.spin.gz");
+    let chg = ChgBase::from_file(&path)?;
+    let plain = get_fpath_in_curr_dir!("CHGCAR_spin_component.vasp");
+    chg.write_file(&plain, ChgType::Chgcar)?;
+    let chg_plain = ChgBase::from_file(&plain)?;
+
+    for path in &[&path, &plain] {
+        let meta = ChgBase::scan_metadata(path)?;
+        let reference = if *path == &plain { &chg_plain } else { &chg };
+        for &component in &[Component::Total, Component::Diff(0)] {
+            let (grid, aug) = match component {
+                Component::Total => (reference.get_total_chg(), reference.get_total_aug().unwrap()),
+                Component::Diff(k) => (&reference.get_diff_chg()[k], &reference.get_diff_aug()[k]),
+            };
+            assert_eq!(&ChgBase::read_grid(path, component)?, grid);
+            assert_eq!(&ChgBase::read_grid_at(path, &meta, component)?, grid);
+            assert_eq!(ChgBase::read_aug(path, component)?.as_ref(), Some(aug));
+            assert_eq!(ChgBase::read_aug_at(path, &meta, component)?.as_ref(), Some(aug));
+        }
+        assert!(ChgBase::read_grid(path, Component::Diff(1)).is_err());
+        assert!(ChgBase::read_grid_at(path, &meta, Component::Diff(1)).is_err());
+    }
+
+    remove_file(&plain)?;
+    Ok(())
+}
+
+#[test]
+fn test_lazy() -> io::Result<()> {
+    let path = get_fpath_in_curr_dir!("CHGCAR.spin.gz");
+    let chg = ChgBase::from_file(&path)?;
+    let plain = get_fpath_in_curr_dir!("CHGCAR_spin_lazy.vasp");
+    chg.write_file(&plain, ChgType::Chgcar)?;
+    let chg_plain = ChgBase::from_file(&plain)?;
+
+    for &(path, expected) in &[(&path, &chg), (&plain, &chg_plain)] {
+        let lazy = LazyChg::from_file(path)?;
+        assert_eq!(lazy.get_ngrid(), expected.get_ngrid());
+        assert_eq!(lazy.get_total_chg(), expected.get_total_chg());
+        assert_eq!(lazy.get_total_aug(), expected.get_total_aug());
+        assert_eq!(lazy.get_ndiff(), 1);
+        assert!(!lazy.is_diff_loaded(0));
+        assert_eq!(lazy.get_diff_chg(0)?, &expected.get_diff_chg()[0]);
+        assert!(lazy.is_diff_loaded(0));
+        assert_eq!(lazy.get_diff_chg(0)?, &expected.get_diff_chg()[0]);
+        assert_eq!(lazy.get_diff_aug(0)?, &expected.get_diff_aug()[0]);
+        assert!(lazy.get_diff_chg(1).is_err());
+        assert!(lazy.get_diff_aug(1).is_err());
+
+        let full = LazyChg::from_file(path)?.into_chgbase()?;
+        assert_eq!(full.get_total_chg(), expected.get_total_chg());
+        assert_eq!(full.get_diff_chg(), expected.get_diff_chg());
+        assert_eq!(full.get_diff_aug(), expected.get_diff_aug());
+    }
+
+    let opts = ReadOptions::default();
+    let expected = ChgBase::<f32>::from_file_as(&plain, &opts)?;
+    let lazy = LazyChg::<f32>::from_file_as(&plain, &opts)?;
+    assert_eq!(lazy.get_total_chg(), expected.get_total_chg());
+    assert_eq!(lazy.get_diff_chg(0)?, &expected.get_diff_chg()[0]);
+    assert_eq!(lazy.into_chgbase()?.get_diff_chg(), expected.get_diff_chg());
+
+    for opts in &[ReadOptions { lossless: true, ..Default::default() },
+                  ReadOptions { provenance: true, ..Default::default() }] {
+        let err = io::Error::from(LazyChg::from_file_with(&plain, opts).err().unwrap());
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    remove_file(&plain)?;
+    Ok(())
+}
+
+#[test]
+fn test_chg_layout() -> io::Result<()> {
+    let path = get_fpath_in_curr_dir!("CHGCAR.spin.gz");
+    let chgcar = ChgBase::from_file(&path)?;
+    assert_eq!(chgcar.get_magmom()[0].trim(), "0.600000000000E+00");
+    assert!(!chgcar.get_total_aug().unwrap().contains("0.600000000000E+00"));
+
+    // a spin-polarized CHG has the magnetic moments but no augmentation occupancies
+    let out = get_fpath_in_curr_dir!("CHG_spin_layout.vasp");
+    chgcar.write_file(&out, ChgType::Chg)?;
+    let chg = ChgBase::from_file(&out)?;
+    assert_eq!(chg.get_chgtype(), ChgType::Chg);
+    assert_eq!(ChgBase::scan_metadata(&out)?.get_chgtype(), ChgType::Chg);
+    assert_eq!(chg.get_magmom(), chgcar.get_magmom());
+    assert_eq!(LazyChg::from_file(&out)?.into_chgbase()?.get_magmom(), chgcar.get_magmom());
+    let mut written = vec![];
+    chg.write_writer(&mut written, chg.get_chgtype())?;
+    assert!(written == std::fs::read(&out)?, "CHG written back differs");
+    remove_file(&out)?;
+    Ok(())
+}
+
+#[test]
+fn test_lossless() -> io::Result<()> {
+    let path = get_fpath_in_curr_dir!("CHGCAR.spin.gz");
+    let mut original = vec![];
+    GzDecoder::new(File::open(&path)?).read_to_end(&mut original)?;
+
+    let opts = ReadOptions { lossless: true, ..Default::default() };
+    let chg = ChgBase::from_file_with(&path, &opts)?;
+    let mut written = vec![];
+    chg.write_writer(&mut written, ChgType::Chgcar)?;
+    assert!(written == original, "lossless output differs from the original");
+    Ok(())
+}
+
+#[test]
+fn test_chg_writer() -> io::Result<()> {
+    let path = get_fpath_in_curr_dir!("CHGCAR.spin.gz");
+    let chg = ChgBase::from_file(&path)?;
+    let mut expected = vec![];
+    chg.write_writer(&mut expected, ChgType::Chgcar)?;
+
+    let mut writer = ChgWriter::new(vec![], chg.get_poscar(), *chg.get_ngrid(), ChgType::Chgcar)?;
+    chg.get_total_chg().axis_iter(Axis(2)).try_for_each(|slab| writer.write_slab(slab))?;
+    writer.write_aug(chg.get_total_aug().unwrap())?;
+    writer.write_magmom(&chg.get_magmom()[0])?;
+    writer.next_component()?;
+    chg.get_diff_chg()[0].axis_iter(Axis(2)).try_for_each(|slab| writer.write_slab(slab))?;
+    writer.write_aug(&chg.get_diff_aug()[0])?;
+    assert!(writer.finish()? == expected, "streamed output differs from write_writer");
+    Ok(())
+}
+
+#[test]
+fn test_spin_channels() -> io::Result<()> {
+    let path = get_fpath_in_curr_dir!("CHGCAR.spin.gz");
+    let chg = ChgBase::from_file(&path)?;
+    let prefix = get_fpath_in_curr_dir!("PARCHG_spin_test");
+    chg.write_spin_channels(&prefix, ChgType::Parchg)?;
+
+    let up = ChgBase::from_file(&get_fpath_in_curr_dir!("PARCHG_spin_test_up"))?;
+    let down = ChgBase::from_file(&get_fpath_in_curr_dir!("PARCHG_spin_test_down"))?;
+    assert_eq!(up.get_chgtype(), ChgType::Parchg);
+    assert!(down.get_diff_chg().is_empty());
+    // spin up + spin down gives back the total, up - down the magnetization, as written to file
+    let volume = chg.get_poscar().scaled_volume();
+    let total = &up.get_total_chg().view() + &down.get_total_chg().view();
+    let mag = (up.get_total_chg() - down.get_total_chg()) * volume;
+    for (a, b) in total.iter().zip(chg.get_total_chg()).chain(mag.iter().zip(&chg.get_diff_chg()[0])) {
+        assert!((a - b).abs() <= 1E-9 * b.abs().max(1.0), "{} != {}", a, b);
+    }
+
+    assert!(chg.write_spin_channels(&prefix, ChgType::Chgcar).is_err());
+    remove_file(&get_fpath_in_curr_dir!("PARCHG_spin_test_up"))?;
+    remove_file(&get_fpath_in_curr_dir!("PARCHG_spin_test_down"))?;
+    Ok(())
+}
@@ -1,4 +1,6 @@
 #![allow(non_snake_case)]
+// the fixtures are gzipped
+#![cfg(feature = "gzip")]
 mod spin { mod test; }
 mod no_spin { mod test; }
 mod soc { mod test; }